@@ -0,0 +1,42 @@
+use std::{error::Error, fs, path::Path};
+
+use chrono::Local;
+use console::style;
+use log::{debug, info};
+use post_archiver::utils::DATABASE_NAME;
+
+/// Copy the database to `<database>.bak.<timestamp>`, keeping at most `max` backups
+pub fn backup_database(output: &Path, max: Option<usize>) -> Result<(), Box<dyn Error>> {
+    let database = output.join(DATABASE_NAME);
+    if !database.is_file() {
+        return Ok(());
+    }
+
+    let prefix = format!("{}.bak.", DATABASE_NAME);
+    let backup = output.join(format!("{}{}", prefix, Local::now().format("%Y%m%d%H%M%S")));
+    fs::copy(&database, &backup)?;
+    info!("Database backup: {}", style(backup.display()).bold());
+
+    let Some(max) = max else {
+        return Ok(());
+    };
+
+    // the timestamps sort by name
+    let mut backups: Vec<_> = fs::read_dir(output)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+        })
+        .collect();
+    backups.sort();
+
+    let prune = backups.len().saturating_sub(max.max(1));
+    for backup in backups.drain(..prune) {
+        debug!(" removing backup: {}", backup.display());
+        fs::remove_file(backup)?;
+    }
+
+    Ok(())
+}
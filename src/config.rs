@@ -1,75 +1,852 @@
-use clap::{Parser, ValueEnum};
+use chrono::format::{Item, StrftimeItems};
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgAction, Command, CommandFactory, FromArgMatches,
+    Parser, ValueEnum,
+};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use dotenv::dotenv;
-use indicatif::MultiProgress;
+use encoding_rs::Encoding;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+#[cfg(not(feature = "tracing"))]
 use indicatif_log_bridge::LogWrapper;
+use log::{debug, info, LevelFilter};
+use regex::Regex;
+use serde::Deserialize;
 use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    ffi::{OsStr, OsString},
     fmt::Display,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
     path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    creator::PLATFORM_NAME,
+    post::{
+        dedup::DedupIndex,
+        file::{DefaultProvider, ExtendedProvider, ExtraMetadataProvider, HashCache},
+        stats::ProgressCounters,
+        transform::CHUNK_SIZE,
+        PostKey,
+    },
 };
 
+pub const DEFAULT_PROGRESS_STYLE: &str = " {prefix:.bold} {bar} {pos}/{len} {wide_msg}";
+pub const DEFAULT_SECONDARY_STYLE: &str = " {prefix:.dim} {bar} {pos}/{len} {wide_msg}";
+
+/// 32 MiB, SQLite defaults to 2 MiB
+const DEFAULT_SQLITE_CACHE_SIZE: i64 = -32768;
+
+const DEFAULT_PART_EXTENSIONS: [&str; 3] = ["part", "crdownload", "tmp"];
+
 #[derive(Debug, Clone, Parser, Default)]
+#[command(
+    after_help = "Every option can also be set by `FANBOXDL_<OPTION>`, e.g. `FANBOXDL_TRANSFORM=move`"
+)]
 pub struct Config {
     /// Your fanbox dl archive path
-    #[clap(env = "INPUT")]
-    input: PathBuf,
+    #[arg(value_name = "INPUT", required_unless_present_any = ["input", "input_from_stdin"])]
+    input_path: Option<PathBuf>,
+    /// More fanbox dl archive paths, can be repeated
+    #[arg(short, long, num_args = 1, action = ArgAction::Append)]
+    input: Vec<PathBuf>,
+    /// Read the creator folders from stdin, one path per line, instead of the inputs
+    #[arg(long, conflicts_with = "input")]
+    input_from_stdin: bool,
+    /// Create the missing input folders instead of failing
+    #[arg(long)]
+    auto_create_input: bool,
     /// Which you path want to save
-    #[arg(default_value = "./archive", env = "OUTPUT")]
+    #[arg(default_value = "./archive")]
     output: PathBuf,
+    /// Layout of the fanbox dl archive
+    #[arg(long, default_value = "auto")]
+    input_format: InputFormat,
+    /// Create a separate archive for each creator in the output
+    #[arg(long)]
+    output_per_creator: bool,
     /// Overwrite existing files
     #[arg(short, long)]
     overwrite: bool,
+    /// Skip the files whose target already exists, unless `--overwrite`.
+    /// With `--overwrite-if-newer` the newer sources are still transferred
+    #[arg(long)]
+    skip_already_transferred: bool,
+    /// Log every skipped creator, post and file with the reason
+    #[arg(long)]
+    show_skipped: bool,
+    /// Only overwrite existing files when the source is newer
+    #[arg(long)]
+    overwrite_if_newer: bool,
+    /// Replace characters illegal on Windows in output filenames
+    #[arg(long)]
+    sanitize_filenames: bool,
+    /// Replacement for illegal characters when sanitizing filenames
+    #[arg(long, default_value = "_")]
+    sanitize_replacement: char,
+    /// Title of plan and dated posts, with `{creator}`, `{plan}`, `{date}` and `{name}`
+    #[arg(long, value_name = "TEMPLATE")]
+    post_title_template: Option<String>,
+    /// Source of the posts, with `{creator}`, `{source}`, `{date}`, `{title}` and `{plan}`,
+    /// where source is the creator folder. By default `{source} - {title}` for posts,
+    /// with the title before `--post-title-template`, and `{source} - {plan}yen` for plans.
+    /// Posts are matched by their source on the next sync, so it must not change between runs
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_post_id_template)]
+    post_id_template: Option<String>,
+    /// Truncate post titles and filenames to this many bytes
+    #[arg(long, value_name = "N")]
+    max_title_length: Option<NonZeroUsize>,
+    /// Check the output paths against the name and path limits of the output filesystem,
+    /// warning and fixing the filenames which would exceed them
+    #[arg(long)]
+    output_encoding_check: bool,
+    /// Levels of folders in the post output, keeping the first subfolders of the post.
+    /// 1 puts every file directly in the post folder [default: 1]
+    #[arg(long, value_name = "N")]
+    output_tree_depth: Option<usize>,
     /// Transform method
     #[arg(short, long, default_value = "copy")]
     transform: TransformMethod,
+    /// Copy instead of move when the source looks read-only
+    #[arg(long)]
+    auto_fallback_copy: bool,
+    /// Compress the copied files with zstd, appending `.zst` to their names
+    #[arg(long)]
+    compress_output: bool,
+    /// Level of `--compress-output`
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: i32,
+    /// Unicode normalization of creator and post names
+    #[arg(long, default_value = "nfc")]
+    normalization: Normalization,
+    /// Encoding of the creator folder names, e.g. `shift_jis`, UTF-8 if not set.
+    /// Unix only, Windows already reports the filenames in Unicode
+    #[arg(long, value_parser = parse_encoding)]
+    input_encoding: Option<&'static Encoding>,
     /// Whitelist of creator IDs
     #[arg(short, long, num_args = 0..)]
     whitelist: Vec<String>,
     /// Blacklist of creator IDs
     #[arg(short, long, num_args = 0..)]
     blacklist: Vec<String>,
-    /// Limit the number of concurrent copys
-    #[arg(short, long, default_value = "5")]
-    limit: usize,
+    /// File of `+creator` to whitelist and `-creator` to blacklist, one per line,
+    /// `#` starts a comment
+    #[arg(long, value_name = "PATH")]
+    creator_filter_file: Option<PathBuf>,
+    /// Order of the creators, `mtime` and `size` read the metadata of every creator folder
+    #[arg(long, default_value = "alphabetical")]
+    input_sort: InputSort,
+    /// Process the creators by the size of their folders, `--input-sort size` is `desc`
+    #[arg(
+        long = "creators-sort-by-size",
+        value_name = "ORDER",
+        conflicts_with = "input_sort"
+    )]
+    sort_creators_by_size: Option<SortOrder>,
+    /// Treat zip archives as regular files instead of extracting them
+    #[arg(long)]
+    no_zip_extract: bool,
+    /// Read the targets of symlinks instead of skipping them,
+    /// folders are read at most 5 levels deep
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Date the undated posts of a zip archive by its name, e.g. `2024-01-15.zip`,
+    /// or by its modified time
+    #[arg(long)]
+    extract_archive_date_from_zip_metadata: bool,
+    /// Date the post folders without a date in the name by their earliest file,
+    /// instead of ignoring them
+    #[arg(long)]
+    infer_post_date_from_files: bool,
+    /// Warn about the post folders without a `post.json`, which are likely incomplete downloads
+    #[arg(long)]
+    warn_on_missing_post_json: bool,
+    /// Only include files with these extensions
+    #[arg(long, num_args = 0..)]
+    include_extensions: Vec<String>,
+    /// Exclude files with these extensions
+    #[arg(long, num_args = 0..)]
+    exclude_extensions: Vec<String>,
+    /// Skip files without an extension
+    #[arg(long)]
+    skip_files_without_extension: bool,
+    /// Additional gitignore patterns applied to every creator
+    #[arg(long, value_name = "PATH")]
+    ignore_file: Option<PathBuf>,
+    /// Skip partial downloads (part, crdownload, tmp), enabled by default
+    #[arg(long, overrides_with = "no_skip_part_files")]
+    skip_part_files: bool,
+    /// Don't skip the default partial download extensions
+    #[arg(long)]
+    no_skip_part_files: bool,
+    /// Additional extensions treated as partial downloads
+    #[arg(long, value_name = "EXTENSION", num_args = 0..)]
+    extra_part_extensions: Vec<String>,
+    /// Order of the files within a post, which is their display order in the archive
+    #[arg(long, alias = "file-order-within-post", default_value = "none")]
+    file_sort: FileSort,
+    /// Same as `--file-sort name`
+    #[arg(long, conflicts_with_all = ["file_sort", "sort_files_by_mtime"])]
+    sort_files_by_name: bool,
+    /// Same as `--file-sort mtime`
+    #[arg(long, conflicts_with = "file_sort")]
+    sort_files_by_mtime: bool,
+    /// Skip images narrower than this
+    #[arg(long, value_name = "PIXELS")]
+    min_image_width: Option<u32>,
+    /// Skip images shorter than this
+    #[arg(long, value_name = "PIXELS")]
+    min_image_height: Option<u32>,
+    /// Also skip files whose image size can't be detected
+    #[arg(long)]
+    strict_image_filter: bool,
+    /// Preset of `--limit`, `--parallel-file-reads` and `--concurrent-creators`,
+    /// the options set explicitly take precedence
+    #[arg(long, default_value = "balanced")]
+    concurrency_profile: ConcurrencyProfile,
+    /// Limit the number of concurrent copys [default: 5]
+    #[arg(short, long)]
+    limit: Option<usize>,
+    /// Hard link files whose hash is already archived by any creator instead of
+    /// transferring them, implies `--extended-metadata`
+    #[arg(long = "dedup", alias = "cross-creator-dedup")]
+    dedup_files: bool,
+    /// Hard link files to an archived file of the same name instead of transferring them
+    #[arg(long)]
+    link_existing_by_name: bool,
+    /// Only link files by name when the sizes match as well
+    #[arg(long, requires = "link_existing_by_name")]
+    name_match_requires_size_match: bool,
+    /// Also store the hash, video duration and pdf pages of files
+    #[arg(long)]
+    extended_metadata: bool,
+    /// Hash of the extended metadata and `--dedup`
+    #[arg(long, default_value = "sha256")]
+    hash_algorithm: HashAlgorithm,
+    /// Keep the file hashes in this file across runs, by path, modified time and size
+    #[arg(long, value_name = "PATH")]
+    file_hash_cache_file: Option<PathBuf>,
+    /// Cache the image sizes in `<file>.dim` next to the images in the input,
+    /// the caches are left in the input by `--transform move`
+    #[arg(long)]
+    dim_cache: bool,
+    /// Limit the number of files whose metadata is read at once [default: 4]
+    #[arg(long, alias = "concurrent-reads")]
+    parallel_file_reads: Option<usize>,
+    /// Abort on the first failed file, the batch is rolled back
+    #[arg(long)]
+    fail_fast: bool,
+    /// Fail the files whose transfer takes longer, e.g. when a network share stops responding
+    #[arg(long, value_name = "SECS")]
+    file_transform_timeout: Option<u64>,
+    /// Template of the post progress bar
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_template)]
+    progress_style: Option<String>,
+    /// Template of the file progress bar
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_template)]
+    secondary_style: Option<String>,
+    /// Limit the copy throughput in bytes per second, 0 is unlimited
+    #[arg(long, default_value = "0", value_name = "BYTES_PER_SEC")]
+    bandwidth_limit: u32,
+    /// Buffer size of the limited and compressed copies, 64KiB to 1MiB usually
+    /// performs best, larger buffers make the bandwidth limit burstier [default: 65536]
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1024..))]
+    read_buffer_size: Option<u32>,
+    /// Limit the number of creator directories read in parallel,
+    /// the database is still written by one creator at a time [default: 1]
+    #[arg(long)]
+    concurrent_creators: Option<usize>,
+    /// Wait between syncing the creators, for rate limited sources
+    #[arg(long, value_name = "MS")]
+    wait_between_creators: Option<u64>,
+    /// Number of posts committed per database transaction
+    #[arg(long, default_value = "1")]
+    batch_size: usize,
+    /// Link of created aliases, `{creator}` and `{platform}` are replaced
+    #[arg(
+        long = "creator-alias-link-template",
+        value_name = "TEMPLATE",
+        default_value = "https://{creator}.fanbox.cc/",
+        value_parser = parse_link_template
+    )]
+    alias_link_template: String,
+    /// Copy the database to `post-archiver.db.bak.<timestamp>` before syncing
+    #[arg(long)]
+    keep_database_backup: bool,
+    /// Exit with code 4 when the output lacks the space to copy the posts of a creator
+    #[arg(long)]
+    check_disk_space: bool,
+    /// Exit with code 5 when no creator is found in the inputs
+    #[arg(long)]
+    error_on_empty_input: bool,
+    /// Keep only the newest N database backups
+    #[arg(long, value_name = "N", requires = "keep_database_backup")]
+    max_backups: Option<usize>,
+    /// SQLite journal mode, `wal` is recommended when the archive is read by another application
+    #[arg(long, value_name = "MODE")]
+    sqlite_journal_mode: Option<JournalMode>,
+    /// SQLite page cache size, negative values are in KiB [default: -32768]
+    #[arg(long, value_name = "PAGES", allow_negative_numbers = true)]
+    sqlite_cache_size: Option<i64>,
+    /// Only transform the files into `<output>/<creator>/<title>`, without database
+    #[arg(long)]
+    no_database: bool,
+    /// Date prefix of post directories with `--no-database`, `/` creates subdirectories
+    #[arg(long, value_name = "STRFTIME", default_value = "%Y-%m-%d", value_parser = parse_date_format)]
+    output_date_format: String,
+    /// Don't create aliases for imported creators
+    #[arg(long)]
+    no_alias: bool,
+    /// Name of a new author instead of the creator folder name, e.g. `abc123=Alice`
+    #[arg(long = "creator-name-override", value_name = "ID=NAME", value_parser = parse_name_override)]
+    creator_name_overrides: Vec<(String, String)>,
+    /// Skip updating author records, only process posts of known creators
+    #[arg(long)]
+    skip_creator_sync: bool,
+    /// Create the platform when it's missing, even with `--skip-creator-sync`
+    #[arg(long)]
+    force_platform_creation: bool,
+    /// Name of the platform, keep it the same for an archive [default: fanbox-dl]
+    #[arg(long, value_name = "NAME")]
+    platform_display_name: Option<String>,
+    /// Only import posts whose folder name, without the date, matches the regex
+    #[arg(long, value_name = "REGEX")]
+    post_filter: Option<Regex>,
+    /// Apply the post filter to ungrouped and plan posts as well
+    #[arg(long)]
+    filter_all_post_types: bool,
+    /// Limit the number of posts processed per creator
+    #[arg(long, value_name = "N")]
+    max_posts_per_creator: Option<usize>,
+    /// Keep the newest posts when limiting posts per creator
+    #[arg(long)]
+    most_recent_first: bool,
+    /// Tags attached to every synced post
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+    /// Tag the plan posts with their tier, e.g. `500yen`
+    #[arg(long)]
+    tag_by_plan: bool,
+    /// Tag the posts with their creator folder name
+    #[arg(long)]
+    tag_by_creator: bool,
+    /// Write a thumbnail.jpg of the first image of each post, fitting in SIZE x SIZE
+    #[arg(long, value_name = "SIZE")]
+    create_thumbnails: Option<u32>,
+    /// Regenerate existing thumbnails
+    #[arg(long)]
+    thumbnail_overwrite: bool,
+    /// Write an index.json of the synced posts for each creator, in `indexes/<creator>`
+    /// of the archive, the archive root with `--output-per-creator`
+    /// or the creator folder with `--no-database`
+    #[arg(long)]
+    create_index: bool,
+    /// Prefix the post sources with this url and the path relative to the input,
+    /// instead of the absolute path. Existing posts are matched by their source
+    #[arg(long, value_name = "URL")]
+    source_base_url: Option<String>,
+    /// Write the sync statistics of each creator to a CSV file
+    #[arg(long, value_name = "PATH")]
+    creator_statistics_file: Option<PathBuf>,
+    /// Write the files whose source went missing during the sync to a JSON file
+    #[arg(long, value_name = "PATH")]
+    export_missing_files: Option<PathBuf>,
+    /// Serve the sync progress as Prometheus metrics on this port
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Address to serve the metrics on, `0.0.0.0` for every interface [default: 127.0.0.1]
+    #[arg(long, value_name = "IP")]
+    metrics_address: Option<IpAddr>,
+    /// Export all creators in the archive to a CSV file and exit
+    #[arg(long, value_name = "PATH")]
+    export_creators_csv: Option<PathBuf>,
+    /// Verify archived files against their stored hash and exit
+    #[arg(long)]
+    check_integrity: bool,
+    /// Re-extract the metadata of archived files missing any field and exit
+    #[arg(long)]
+    refresh_metadata: bool,
+    /// Print the found posts as JSON lines and exit, without opening the archive
+    #[arg(long)]
+    list_posts: bool,
     #[command(flatten)]
     pub verbose: Verbosity<InfoLevel>,
+    /// Disable colored output, also set by `NO_COLOR`
+    #[arg(long)]
+    no_color: bool,
+    /// Log level of a module, e.g. `post::file=debug`
+    #[arg(long = "log-level-per-module", value_name = "MODULE=LEVEL", value_parser = parse_module_level)]
+    module_log_levels: Vec<(String, LevelFilter)>,
+    /// Append the log to this file instead of the terminal
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Rotate the log file at startup, keeping at most N rotated copies `<log>.1` .. `<log>.N`
+    #[arg(long, value_name = "N", requires = "log_file")]
+    rotate_logs: Option<usize>,
+    /// Size of the log file to rotate it at [default: 10485760]
+    #[arg(long, value_name = "BYTES", requires = "rotate_logs")]
+    log_rotate_size: Option<u64>,
     #[clap(skip)]
     multi: MultiProgress,
+    #[clap(skip)]
+    bandwidth: Option<Arc<DefaultDirectRateLimiter>>,
+    #[clap(skip)]
+    reads: Option<Arc<Semaphore>>,
+    #[clap(skip)]
+    dedup: Option<DedupIndex>,
+    #[clap(skip)]
+    names: Option<DedupIndex>,
+    #[clap(skip)]
+    hash_cache: Option<Arc<Mutex<HashCache>>>,
+    #[clap(skip)]
+    progress: Arc<ProgressCounters>,
+    #[clap(skip)]
+    processed: Arc<Mutex<HashSet<PostKey>>>,
 }
 
 impl Config {
     /// Parse the configuration from the environment and command line arguments
-    pub fn parse() -> Self {
+    pub fn try_parse() -> Result<Self, clap::Error> {
         dotenv().ok();
-        <Self as Parser>::parse()
+        Self::try_parse_from(std::env::args_os())
+    }
+    /// Parse the configuration from the environment and the given arguments,
+    /// the first one is the binary name
+    pub fn try_parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = prefix_env(Self::command()).try_get_matches_from(args)?;
+        let mut config = Self::from_arg_matches(&matches)?;
+
+        // with `--input` or stdin the only positional is the output
+        if let Some(path) = config.input_path.take() {
+            let inputs_given = !config.input.is_empty() || config.input_from_stdin;
+            let output_given = matches.value_source("output") != Some(ValueSource::DefaultValue);
+            if inputs_given && !output_given {
+                config.output = path;
+            } else if config.input_from_stdin {
+                let message = "an input path can't be used with '--input-from-stdin'";
+                return Err(Self::command().error(ErrorKind::ArgumentConflict, message));
+            } else {
+                config.input.insert(0, path);
+            }
+        }
+
+        if let Some(path) = config.creator_filter_file.clone() {
+            if let Err(e) = config.load_creator_filter(&path) {
+                let message = format!("{}: {}", path.display(), e);
+                return Err(Self::command().error(ErrorKind::Io, message));
+            }
+        }
+
+        // shared by all file tasks, so the limit is global
+        if let Some(limit) = NonZeroU32::new(config.bandwidth_limit) {
+            let burst = limit.max(NonZeroU32::new(config.read_buffer_size() as u32).unwrap());
+            let quota = Quota::per_second(limit).allow_burst(burst);
+            config.bandwidth = Some(Arc::new(RateLimiter::direct(quota)));
+        }
+
+        if config.dedup_files {
+            config.dedup = Some(DedupIndex::default());
+        }
+        if config.link_existing_by_name {
+            config.names = Some(DedupIndex::default());
+        }
+        config.reads = Some(Arc::new(Semaphore::new(config.parallel_file_reads())));
+
+        if let Some(path) = &config.file_hash_cache_file {
+            let cache = match HashCache::load(path, config.hash_algorithm) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    let message = format!("{}: {}", path.display(), e);
+                    return Err(Self::command().error(ErrorKind::Io, message));
+                }
+            };
+            config.hash_cache = Some(Arc::new(Mutex::new(cache)));
+        }
+
+        // https://no-color.org/
+        config.no_color |= std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        if config.no_color || config.log_file.is_some() {
+            console::set_colors_enabled(false);
+            console::set_colors_enabled_stderr(false);
+        }
+
+        Ok(config)
     }
     /// Create a logger with the configured verbosity level
-    pub fn init_logger(&self) {
+    #[cfg(not(feature = "tracing"))]
+    pub fn init_logger(&self) -> Result<(), Box<dyn Error>> {
+        let level = self.verbose.log_level_filter();
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(level).format_target(false);
+        if self.no_color {
+            builder.write_style(env_logger::WriteStyle::Never);
+        }
+        if let Some(file) = self.open_log_file()? {
+            builder
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .write_style(env_logger::WriteStyle::Never);
+        }
+        for (module, level) in self.module_log_levels.iter() {
+            builder.filter_module(module, *level);
+        }
+        let logger = builder.build();
+
+        LogWrapper::new(self.multi.clone(), logger).try_init()?;
+
+        log::set_max_level(self.max_log_level());
+        Ok(())
+    }
+    /// Create a tracing subscriber with the configured verbosity level,
+    /// `log` records are forwarded to it as events
+    #[cfg(feature = "tracing")]
+    pub fn init_logger(&self) -> Result<(), Box<dyn Error>> {
+        use tracing_subscriber::{
+            filter::{LevelFilter, Targets},
+            layer::SubscriberExt,
+            util::SubscriberInitExt,
+        };
+
+        let to_filter =
+            |level: log::LevelFilter| level.as_str().parse().unwrap_or(LevelFilter::OFF);
         let level = self.verbose.log_level_filter();
-        let logger = env_logger::Builder::new()
-            .filter_level(level)
-            .format_target(false)
-            .build();
+        let targets = self.module_log_levels.iter().fold(
+            Targets::new().with_default(to_filter(level)),
+            |targets, (module, level)| targets.with_target(module, to_filter(*level)),
+        );
+        let multi = self.multi.clone();
+        let file = self.open_log_file()?.map(Arc::new);
+        let ansi = !self.no_color && file.is_none();
 
-        LogWrapper::new(self.multi.clone(), logger)
-            .try_init()
-            .unwrap();
+        tracing_subscriber::fmt()
+            .with_max_level(to_filter(self.max_log_level()))
+            .with_target(false)
+            .with_ansi(ansi)
+            .with_writer(move || ProgressWriter(multi.clone(), file.clone()))
+            .finish()
+            .with(targets)
+            .try_init()?;
 
-        log::set_max_level(level);
+        log::set_max_level(self.max_log_level());
+        Ok(())
+    }
+    /// Rotate and open the log file, if any
+    fn open_log_file(&self) -> io::Result<Option<fs::File>> {
+        let Some(path) = self.log_file.as_ref() else {
+            return Ok(None);
+        };
+        let rotate = |keep| rotate_log(path, keep, self.log_rotate_size.unwrap_or(10 << 20));
+        self.rotate_logs
+            .map_or(Ok(()), rotate)
+            .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(path))
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+    /// The most verbose of the global and module levels
+    fn max_log_level(&self) -> LevelFilter {
+        self.module_log_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.verbose.log_level_filter(), Ord::max)
+    }
+    pub fn input(&self) -> &[PathBuf] {
+        &self.input
+    }
+    pub fn input_from_stdin(&self) -> bool {
+        self.input_from_stdin
+    }
+    pub fn auto_create_input(&self) -> bool {
+        self.auto_create_input
     }
-    pub fn input(&self) -> &Path {
-        self.input.as_path()
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
     }
     pub fn overwrite(&self) -> bool {
         self.overwrite
     }
+    pub fn skip_already_transferred(&self) -> bool {
+        self.skip_already_transferred && !self.overwrite
+    }
+    pub fn overwrite_if_newer(&self) -> bool {
+        self.overwrite_if_newer
+    }
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast
+    }
+    pub fn file_transform_timeout(&self) -> Option<Duration> {
+        self.file_transform_timeout.map(Duration::from_secs)
+    }
     pub fn transform(&self) -> TransformMethod {
         self.transform
     }
+    /// Only the copy transform writes new files
+    pub fn compress_output(&self) -> Option<i32> {
+        (self.compress_output && self.transform == TransformMethod::Copy)
+            .then_some(self.compression_level)
+    }
+    pub fn auto_fallback_copy(&self) -> bool {
+        self.auto_fallback_copy
+    }
+    pub fn limit(&self) -> usize {
+        let (limit, _, _) = self.concurrency_profile.sizes();
+        self.limit.unwrap_or(limit).max(1)
+    }
+    pub fn bandwidth(&self) -> Option<&Arc<DefaultDirectRateLimiter>> {
+        self.bandwidth.as_ref()
+    }
+    pub fn read_buffer_size(&self) -> usize {
+        self.read_buffer_size
+            .map_or(CHUNK_SIZE, |size| size as usize)
+    }
+    pub fn dedup(&self) -> Option<&DedupIndex> {
+        self.dedup.as_ref()
+    }
+    /// Digests of the hashed files, with `--file-hash-cache-file`
+    pub fn hash_cache(&self) -> Option<(&Path, &Mutex<HashCache>)> {
+        let path = self.file_hash_cache_file.as_deref()?;
+        Some((path, self.hash_cache.as_deref()?))
+    }
+    /// Archived file of each filename, with `--link-existing-by-name`
+    pub fn names(&self) -> Option<&DedupIndex> {
+        self.names.as_ref()
+    }
+    pub fn name_match_requires_size_match(&self) -> bool {
+        self.name_match_requires_size_match
+    }
+    pub fn metadata_provider(&self) -> Arc<dyn ExtraMetadataProvider> {
+        let provider = DefaultProvider {
+            dim_cache: self.dim_cache,
+        };
+        match self.extended_metadata || self.dedup_files {
+            true => Arc::new(ExtendedProvider {
+                default: provider,
+                hash: self.hash_algorithm,
+                cache: self.hash_cache.clone(),
+            }),
+            false => Arc::new(provider),
+        }
+    }
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+    /// Shared by all creators, so the limit is global
+    pub fn reads(&self) -> Arc<Semaphore> {
+        self.reads
+            .clone()
+            .unwrap_or_else(|| Arc::new(Semaphore::new(self.parallel_file_reads())))
+    }
+    fn parallel_file_reads(&self) -> usize {
+        let (_, reads, _) = self.concurrency_profile.sizes();
+        self.parallel_file_reads.unwrap_or(reads).max(1)
+    }
     pub fn output(&self) -> &PathBuf {
         &self.output
     }
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+    pub fn no_zip_extract(&self) -> bool {
+        self.no_zip_extract
+    }
+    pub fn infer_post_date_from_files(&self) -> bool {
+        self.infer_post_date_from_files
+    }
+    pub fn warn_on_missing_post_json(&self) -> bool {
+        self.warn_on_missing_post_json
+    }
+    pub fn extract_archive_date_from_zip_metadata(&self) -> bool {
+        self.extract_archive_date_from_zip_metadata
+    }
+    pub fn input_sort(&self) -> InputSort {
+        self.input_sort
+    }
+    pub fn sort_creators_by_size(&self) -> Option<SortOrder> {
+        self.sort_creators_by_size
+    }
+    pub fn concurrent_creators(&self) -> usize {
+        let (_, _, creators) = self.concurrency_profile.sizes();
+        self.concurrent_creators.unwrap_or(creators).max(1)
+    }
+    pub fn wait_between_creators(&self) -> Option<Duration> {
+        self.wait_between_creators.map(Duration::from_millis)
+    }
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.max(1)
+    }
+    /// Display name of the creator, the folder name if not overridden
+    pub fn creator_name<'a>(&'a self, creator: &'a str) -> &'a str {
+        self.creator_name_overrides
+            .iter()
+            .find(|(id, _)| id == creator)
+            .map_or(creator, |(_, name)| name)
+    }
+    pub fn alias_link(&self, creator: &str, platform: &str) -> String {
+        self.alias_link_template
+            .replace("{creator}", creator)
+            .replace("{platform}", platform)
+    }
+    pub fn check_disk_space(&self) -> bool {
+        self.check_disk_space
+    }
+    pub fn error_on_empty_input(&self) -> bool {
+        self.error_on_empty_input
+    }
+    pub fn keep_database_backup(&self) -> bool {
+        self.keep_database_backup
+    }
+    pub fn max_backups(&self) -> Option<usize> {
+        self.max_backups
+    }
+    pub fn sqlite_journal_mode(&self) -> Option<JournalMode> {
+        self.sqlite_journal_mode
+    }
+    pub fn sqlite_cache_size(&self) -> i64 {
+        self.sqlite_cache_size.unwrap_or(DEFAULT_SQLITE_CACHE_SIZE)
+    }
+    pub fn no_database(&self) -> bool {
+        self.no_database
+    }
+    pub fn output_date_format(&self) -> &str {
+        &self.output_date_format
+    }
+    pub fn no_alias(&self) -> bool {
+        self.no_alias
+    }
+    pub fn sanitize_filenames(&self) -> bool {
+        self.sanitize_filenames
+    }
+    pub fn sanitize_replacement(&self) -> char {
+        self.sanitize_replacement
+    }
+    pub fn post_title_template(&self) -> Option<&str> {
+        self.post_title_template.as_deref()
+    }
+    pub fn post_id_template(&self) -> Option<&str> {
+        self.post_id_template.as_deref()
+    }
+    pub fn max_title_length(&self) -> Option<usize> {
+        self.max_title_length.map(NonZeroUsize::get)
+    }
+    pub fn output_encoding_check(&self) -> bool {
+        self.output_encoding_check
+    }
+    pub fn output_tree_depth(&self) -> usize {
+        self.output_tree_depth.unwrap_or(1).max(1)
+    }
+    pub fn output_per_creator(&self) -> bool {
+        self.output_per_creator
+    }
+    pub fn skip_creator_sync(&self) -> bool {
+        self.skip_creator_sync
+    }
+    pub fn force_platform_creation(&self) -> bool {
+        self.force_platform_creation
+    }
+    pub fn platform_name(&self) -> &str {
+        self.platform_display_name
+            .as_deref()
+            .unwrap_or(PLATFORM_NAME)
+    }
+    pub fn max_posts_per_creator(&self) -> Option<usize> {
+        self.max_posts_per_creator
+    }
+    pub fn most_recent_first(&self) -> bool {
+        self.most_recent_first
+    }
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn tag_by_plan(&self) -> bool {
+        self.tag_by_plan
+    }
+    pub fn tag_by_creator(&self) -> bool {
+        self.tag_by_creator
+    }
+    pub fn create_thumbnails(&self) -> Option<u32> {
+        self.create_thumbnails
+    }
+    pub fn thumbnail_overwrite(&self) -> bool {
+        self.thumbnail_overwrite
+    }
+    pub fn create_index(&self) -> bool {
+        self.create_index
+    }
+    pub fn creator_statistics_file(&self) -> Option<&PathBuf> {
+        self.creator_statistics_file.as_ref()
+    }
+    pub fn export_missing_files(&self) -> Option<&PathBuf> {
+        self.export_missing_files.as_ref()
+    }
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_port.map(|port| {
+            SocketAddr::new(
+                self.metrics_address.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+                port,
+            )
+        })
+    }
+    pub fn export_creators_csv(&self) -> Option<&Path> {
+        self.export_creators_csv.as_deref()
+    }
+
+    pub fn check_integrity(&self) -> bool {
+        self.check_integrity
+    }
+    pub fn refresh_metadata(&self) -> bool {
+        self.refresh_metadata
+    }
+    pub fn list_posts(&self) -> bool {
+        self.list_posts
+    }
 
+    /// Decode a filename with the input encoding
+    pub fn decode_name(&self, name: &OsStr) -> String {
+        match self.input_encoding {
+            // the raw bytes on Unix, but WTF-8 on Windows
+            Some(encoding) if cfg!(unix) => encoding.decode(name.as_encoded_bytes()).0.into_owned(),
+            _ => name.to_string_lossy().to_string(),
+        }
+    }
+
+    pub fn normalize(&self, name: &str) -> String {
+        match self.normalization {
+            Normalization::Nfc => name.nfc().collect(),
+            Normalization::Nfd => name.nfd().collect(),
+            Normalization::None => name.to_string(),
+        }
+    }
+
+    /// Add the creators of the filter file to the whitelist and blacklist
+    fn load_creator_filter(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match (line.strip_prefix('+'), line.strip_prefix('-')) {
+                (Some(creator), _) => self.whitelist.push(creator.trim().to_string()),
+                (_, Some(creator)) => self.blacklist.push(creator.trim().to_string()),
+                _ => {
+                    return Err(
+                        format!("line {}: expected `+creator` or `-creator`", number + 1).into(),
+                    )
+                }
+            }
+        }
+        Ok(())
+    }
     pub fn filter_creator(&self, creator: &String) -> bool {
         let mut accept = true;
 
@@ -78,16 +855,207 @@ impl Config {
 
         accept
     }
+    /// Source of the posts of a creator path
+    pub fn post_source(&self, path: &Path) -> String {
+        let Some(base_url) = &self.source_base_url else {
+            return path.to_string_lossy().to_string();
+        };
+
+        let relative = self
+            .input
+            .iter()
+            .find_map(|input| path.strip_prefix(input).ok())
+            .unwrap_or(path);
+        let relative: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        format!("{}/{}", base_url.trim_end_matches('/'), relative.join("/"))
+    }
+    pub fn filter_post_title(&self, title: &str) -> bool {
+        self.post_filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(title))
+    }
+    pub fn filter_all_post_types(&self) -> bool {
+        self.filter_all_post_types
+    }
+    pub fn skip_files_without_extension(&self) -> bool {
+        self.skip_files_without_extension
+    }
+    pub fn filter_extension(&self, path: &Path) -> bool {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let matches = |list: &[String]| {
+            list.iter()
+                .any(|item| item.eq_ignore_ascii_case(&extension))
+        };
+
+        let mut accept = true;
+
+        accept &= self.include_extensions.is_empty() || matches(&self.include_extensions);
+        accept &= !matches(&self.exclude_extensions);
+
+        accept
+    }
+    pub fn ignore_file(&self) -> Option<&PathBuf> {
+        self.ignore_file.as_ref()
+    }
+    pub fn skip_part_extensions(&self) -> Vec<String> {
+        let mut extensions = vec![];
+        if self.skip_part_files || !self.no_skip_part_files {
+            extensions.extend(DEFAULT_PART_EXTENSIONS.map(String::from));
+        }
+        extensions.extend(self.extra_part_extensions.iter().cloned());
+        extensions
+    }
+    /// Whether the file is left by an incomplete download
+    pub fn is_part_file(&self, path: &Path) -> bool {
+        let Some(extension) = path.extension() else {
+            return false;
+        };
+        let extension = extension.to_string_lossy();
+        self.skip_part_extensions()
+            .iter()
+            .any(|item| item.eq_ignore_ascii_case(&extension))
+    }
+    pub fn file_sort(&self) -> FileSort {
+        match (self.sort_files_by_name, self.sort_files_by_mtime) {
+            (true, _) => FileSort::Name,
+            (_, true) => FileSort::Mtime,
+            _ => self.file_sort,
+        }
+    }
+    pub fn filter_image_size(&self, dimensions: Option<(u64, u64)>) -> bool {
+        if self.min_image_width.is_none() && self.min_image_height.is_none() {
+            return true;
+        }
+
+        let Some((width, height)) = dimensions else {
+            return !self.strict_image_filter;
+        };
+
+        let mut accept = true;
+
+        accept &= width >= self.min_image_width.unwrap_or_default() as u64;
+        accept &= height >= self.min_image_height.unwrap_or_default() as u64;
+
+        accept
+    }
+    pub fn progress_style(&self) -> ProgressStyle {
+        let template = self.progress_style.as_deref();
+        ProgressStyle::with_template(template.unwrap_or(DEFAULT_PROGRESS_STYLE)).unwrap()
+    }
+    pub fn secondary_style(&self) -> ProgressStyle {
+        let template = self.secondary_style.as_deref();
+        ProgressStyle::with_template(template.unwrap_or(DEFAULT_SECONDARY_STYLE)).unwrap()
+    }
+    /// Report a filtered item, at info level with `--show-skipped`
+    pub fn skip(&self, item: impl Display, reason: &str) {
+        if self.show_skipped {
+            info!("skip: {} ({})", item, reason);
+        } else {
+            debug!(" ignoring: {} ({})", item, reason);
+        }
+    }
+    /// Shared by all creators, reported on `SIGUSR1`
+    pub fn progress(&self) -> &Arc<ProgressCounters> {
+        &self.progress
+    }
+    /// Posts synced into the current archive, shared by its creators
+    pub fn processed(&self) -> &Mutex<HashSet<PostKey>> {
+        &self.processed
+    }
+    /// A progress bar of the shared multi progress, hidden when `hidden`
+    pub fn progress_bar(&self, len: usize, hidden: bool) -> ProgressBar {
+        match hidden {
+            true => ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::hidden()),
+            false => self.multi.add(ProgressBar::new(len as u64)),
+        }
+    }
     pub fn multi(&self) -> &MultiProgress {
         &self.multi
     }
+
+    /// Copy the config with another output path
+    pub fn with_output(&self, output: PathBuf) -> Config {
+        Config {
+            output,
+            ..self.clone()
+        }
+    }
+
+    /// Copy the config without the `.dim` image size cache
+    pub fn without_dim_cache(&self) -> Config {
+        Config {
+            dim_cache: false,
+            ..self.clone()
+        }
+    }
+
+    /// Copy the config with another transform method
+    pub fn with_transform(&self, transform: TransformMethod) -> Config {
+        Config {
+            transform,
+            ..self.clone()
+        }
+    }
+
+    /// Apply the overrides of a creator, the creator-level settings win
+    pub fn merge(&self, creator: &CreatorConfig) -> Config {
+        let mut config = self.clone();
+        if let Some(transform) = creator.transform {
+            config.transform = transform;
+        }
+        if let Some(include_extensions) = &creator.include_extensions {
+            config.include_extensions = include_extensions.clone();
+        }
+        if let Some(exclude_extensions) = &creator.exclude_extensions {
+            config.exclude_extensions = exclude_extensions.clone();
+        }
+        if let Some(tags) = &creator.tags {
+            config.tags = tags.clone();
+        }
+        config
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+/// Per-creator overrides, loaded from `<creator_dir>/.fanboxdlconfig`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CreatorConfig {
+    transform: Option<TransformMethod>,
+    include_extensions: Option<Vec<String>>,
+    exclude_extensions: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+}
+
+impl CreatorConfig {
+    pub const FILENAME: &'static str = ".fanboxdlconfig";
+
+    /// Load the creator config in the directory, if there is one
+    pub fn load(dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = dir.join(Self::FILENAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let config =
+            toml::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+        Ok(Some(config))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransformMethod {
     #[default]
     Copy,
     Move,
+    Reflink,
 }
 
 impl Display for TransformMethod {
@@ -95,6 +1063,293 @@ impl Display for TransformMethod {
         match self {
             TransformMethod::Copy => write!(f, "Copy"),
             TransformMethod::Move => write!(f, "Move"),
+            TransformMethod::Reflink => write!(f, "Reflink"),
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum InputFormat {
+    /// Detect the layout by the directory names
+    #[default]
+    Auto,
+    /// Older layout, every directory is a post
+    FanboxDlV1,
+    /// Plan (`500yen`), dated post (`2024-01-31-title`) and ungrouped files
+    FanboxDlV2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FileSort {
+    /// Filesystem order
+    #[default]
+    #[value(alias = "filesystem")]
+    None,
+    #[value(alias = "alphabetical-asc")]
+    Name,
+    #[value(alias = "alphabetical-desc")]
+    NameReverse,
+    Mtime,
+    MtimeReverse,
+    /// Smallest first
+    #[value(alias = "size-asc")]
+    Size,
+    #[value(alias = "size-desc")]
+    SizeReverse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum InputSort {
+    /// By creator name
+    #[default]
+    Alphabetical,
+    /// Recently modified first
+    Mtime,
+    /// Largest first
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ConcurrencyProfile {
+    /// Many concurrent copies, few metadata reads
+    IoBound,
+    /// Many metadata reads, few concurrent copies
+    CpuBound,
+    /// The default sizes
+    #[default]
+    Balanced,
+}
+
+impl ConcurrencyProfile {
+    /// Sizes of the copy, metadata read and creator semaphores
+    pub fn sizes(&self) -> (usize, usize, usize) {
+        let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        match self {
+            ConcurrencyProfile::IoBound => (cpus * 4, (cpus / 2).max(1), 2),
+            ConcurrencyProfile::CpuBound => (2, cpus, 1),
+            ConcurrencyProfile::Balanced => (5, 4, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Smallest first
+    Asc,
+    /// Largest first
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Normalization {
+    #[default]
+    Nfc,
+    Nfd,
+    None,
+}
+
+/// Write to stderr without tearing the progress bars
+#[cfg(feature = "tracing")]
+struct ProgressWriter(MultiProgress, Option<Arc<fs::File>>);
+
+#[cfg(feature = "tracing")]
+impl std::io::Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &self.1 {
+            Some(file) => (&**file).write(buf),
+            None => self.0.suspend(|| std::io::stderr().write(buf)),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &self.1 {
+            Some(file) => (&**file).flush(),
+            None => std::io::stderr().flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Key of the digest in the extra metadata
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// Move the log to `<log>.1` once it's larger than `max_size`, shifting the older logs up to `<log>.<keep>`
+fn rotate_log(path: &Path, keep: usize, max_size: u64) -> std::io::Result<()> {
+    if fs::metadata(path).map_or(true, |metadata| metadata.len() <= max_size) {
+        return Ok(());
+    }
+
+    let rotated = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        if rotated(n).exists() {
+            fs::rename(rotated(n), rotated(n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(1))
+}
+
+pub const ENV_PREFIX: &str = "FANBOXDL_";
+
+/// `FANBOXDL_<LONG_NAME>` of every argument by id, built once for all the parses
+static ENV_NAMES: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
+    Config::command()
+        .get_arguments()
+        .map(|arg| {
+            let name = arg.get_long().unwrap_or(arg.get_id().as_str());
+            let env = format!("{}{}", ENV_PREFIX, name.replace('-', "_").to_uppercase());
+            (arg.get_id().to_string(), env)
+        })
+        .collect()
+});
+
+/// Read every argument from `FANBOXDL_<LONG_NAME>` as well, e.g. `FANBOXDL_INPUT`.
+/// The unprefixed `INPUT` and `OUTPUT` of the earlier versions are still read when set
+fn prefix_env(command: Command) -> Command {
+    command.mut_args(|arg| {
+        if matches!(
+            arg.get_action(),
+            ArgAction::Help | ArgAction::Version | ArgAction::Count
+        ) {
+            return arg;
+        }
+
+        // listing every variable would clutter the help
+        let id = arg.get_id().as_str();
+        let hide = !matches!(id, "input_path" | "input" | "output");
+        let env = ENV_NAMES[id].as_str();
+        let legacy = match id {
+            "input_path" => Some("INPUT"),
+            "output" => Some("OUTPUT"),
+            _ => None,
+        };
+        let env = match legacy {
+            Some(legacy)
+                if std::env::var_os(env).is_none() && std::env::var_os(legacy).is_some() =>
+            {
+                legacy
+            }
+            _ => env,
+        };
+        arg.env(env).hide_env(hide)
+    })
+}
+
+fn parse_post_id_template(template: &str) -> Result<String, String> {
+    // read order and filters shifted the index, so posts swapped ids between runs
+    match template.contains("{index}") {
+        true => Err("`{index}` is not stable across runs, use the other tokens".to_string()),
+        false => Ok(template.to_string()),
+    }
+}
+
+fn parse_template(template: &str) -> Result<String, String> {
+    ProgressStyle::with_template(template)
+        .map(|_| template.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_date_format(format: &str) -> Result<String, String> {
+    match StrftimeItems::new(format).any(|item| item == Item::Error) {
+        true => Err("invalid strftime format".to_string()),
+        false => Ok(format.to_string()),
+    }
+}
+
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    if cfg!(not(unix)) {
+        return Err("only supported on Unix, the filenames are already Unicode".to_string());
+    }
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding `{}`", label))
+}
+
+fn parse_name_override(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((id, name)) if !id.is_empty() && !name.is_empty() => {
+            Ok((id.to_string(), name.to_string()))
+        }
+        _ => Err("expected `id=name`".to_string()),
+    }
+}
+
+/// Parse `module=level`, the module is relative to this crate
+fn parse_module_level(value: &str) -> Result<(String, LevelFilter), String> {
+    let (module, level) = value
+        .split_once('=')
+        .ok_or_else(|| "expected `module=level`".to_string())?;
+    let level = level
+        .parse()
+        .map_err(|_| format!("unknown log level `{}`", level))?;
+    let module = module.trim_start_matches("crate::");
+    Ok((format!("{}::{}", env!("CARGO_CRATE_NAME"), module), level))
+}
+
+fn parse_link_template(template: &str) -> Result<String, String> {
+    match template.contains("{creator}") {
+        true => Ok(template.to_string()),
+        false => Err("the template must contain `{creator}`".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positional_input_and_output() {
+        let config = Config::try_parse_from(["fanbox-dl-archive", "in", "out"]).unwrap();
+        assert_eq!(config.input(), [PathBuf::from("in")]);
+        assert_eq!(config.output(), Path::new("out"));
+    }
+
+    #[test]
+    fn repeated_input_keeps_output() {
+        let config =
+            Config::try_parse_from(["fanbox-dl-archive", "-i", "a", "-i", "b", "out"]).unwrap();
+        assert_eq!(config.input(), [PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(config.output(), Path::new("out"));
+    }
+}
@@ -1,82 +1,297 @@
-use std::{error::Error, fs, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use console::style;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use post_archiver::{
     importer::{UnsyncAlias, UnsyncAuthor},
-    manager::PostArchiverManager,
+    manager::{PostArchiverConnection, PostArchiverManager},
+    utils::DATABASE_NAME,
     AuthorId, PlatformId,
 };
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{
+    config::{Config, CreatorConfig, InputSort, SortOrder},
+    post::{entry_type, extract::is_zip},
+};
+
+pub const PLATFORM_NAME: &str = "fanbox-dl";
 
-use crate::config::Config;
+pub type Creators = Vec<(String, Vec<PathBuf>, Option<CreatorConfig>)>;
+pub type Authors = Vec<(AuthorId, String, Vec<PathBuf>, Option<CreatorConfig>)>;
 
-pub async fn get_creators(config: &Config) -> Result<Vec<(String, PathBuf)>, Box<dyn Error>> {
+pub async fn get_creators(config: &Config) -> Result<Creators, Box<dyn Error>> {
     info!("Checking creators");
-    let mut creators = vec![];
-    for entry in fs::read_dir(config.input())?.flatten() {
-        let name = entry.file_name().to_string_lossy().to_string();
-        if name.starts_with('.') {
-            debug!(" ignoring: {}", entry.path().display());
-            continue;
+    let mut found: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    if config.input_from_stdin() {
+        read_creators_from_stdin(config, &mut found).await?;
+    }
+    for input in config.input() {
+        if config.auto_create_input() && !tokio::fs::try_exists(input).await? {
+            tokio::fs::create_dir_all(input).await?;
+            warn!("Created empty input directory at {}", input.display());
         }
 
-        let filetype = entry.file_type()?;
-        if !filetype.is_dir() {
-            debug!(" ignoring: {}", entry.path().display());
-            continue;
-        };
+        let mut entries = tokio::fs::read_dir(input).await?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = config.decode_name(&entry.file_name());
+            if name.starts_with('.') {
+                debug!(" ignoring: {}", entry.path().display());
+                continue;
+            }
+
+            let Some(filetype) = entry_type(config, &entry).await? else {
+                continue;
+            };
+            let path = entry.path();
+            let name = if filetype.is_dir() {
+                name
+            } else if filetype.is_file() && !config.no_zip_extract() && is_zip(&path) {
+                config.decode_name(path.file_stem().unwrap())
+            } else {
+                debug!(" ignoring: {}", path.display());
+                continue;
+            };
+            let name = config.normalize(&name);
 
-        creators.push((name, entry.path()));
+            found.entry(name).or_default().push(path);
+        }
     }
 
-    let total = creators.len();
+    let total = found.len();
     info!("{} {}", total, style("total").bold());
-    creators.retain(|(c, _)| config.filter_creator(c));
-    let filtered = creators.len();
+    found.retain(|creator, _| {
+        let accept = config.filter_creator(creator);
+        if !accept {
+            config.skip(creator, "creator filtered");
+        }
+        accept
+    });
+    let filtered = found.len();
     info!("{} {}", filtered, style("included"));
     info!("{} {}", total - filtered, style("excluded").dim());
     info!("");
 
+    let mut creators = vec![];
+    for (creator, paths) in found {
+        let mut creator_config = None;
+        for path in paths.iter() {
+            creator_config = CreatorConfig::load(path)?;
+            if creator_config.is_some() {
+                debug!(" {} loaded for {}", CreatorConfig::FILENAME, creator);
+                break;
+            }
+        }
+        creators.push((creator, paths, creator_config));
+    }
+
+    // the creators are found by name
+    let size = |paths: &Vec<PathBuf>| paths.iter().map(|path| dir_size(path)).sum::<u64>();
+    match (config.input_sort(), config.sort_creators_by_size()) {
+        (_, Some(SortOrder::Asc)) => creators.sort_by_cached_key(|(_, paths, _)| size(paths)),
+        (InputSort::Size, None) | (_, Some(SortOrder::Desc)) => {
+            creators.sort_by_cached_key(|(_, paths, _)| Reverse(size(paths)))
+        }
+        (InputSort::Alphabetical, None) => {}
+        (InputSort::Mtime, None) => creators.sort_by_cached_key(|(_, paths, _)| {
+            let modified = paths.iter().filter_map(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            });
+            Reverse(modified.max())
+        }),
+    }
+
     Ok(creators)
 }
 
-pub fn display_creators(creators: &[(String, PathBuf)]) {
-    if log::log_enabled!(log::Level::Info) {
-        let mut creators: Vec<String> = creators.iter().map(|(c, _)| c.clone()).collect();
-        creators.sort();
-
-        info!("== Creator =============");
+/// Read the creator folders from stdin, named by the last path component
+async fn read_creators_from_stdin(
+    config: &Config,
+    found: &mut BTreeMap<String, Vec<PathBuf>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
 
-        for creator in creators.iter() {
-            info!(" {}", creator);
+        let path = PathBuf::from(line);
+        let Some(name) = path.file_name() else {
+            warn!(" not a creator folder: {}", path.display());
+            continue;
+        };
+        if !tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            warn!(" not a directory: {}", path.display());
+            continue;
         }
 
-        info!("========================");
-        info!("");
+        let name = config.normalize(&config.decode_name(name));
+        found.entry(name).or_default().push(path);
+    }
+    Ok(())
+}
+
+/// Total size of the files under the path
+fn dir_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| dir_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Log the creators, the post and file counts are logged once each creator is read
+pub fn display_creators(creators: &Creators) {
+    if !log::log_enabled!(log::Level::Info) {
+        return;
+    }
+
+    let mut list = creators
+        .iter()
+        .map(|(creator, _, _)| creator.clone())
+        .collect::<Vec<_>>();
+    list.sort();
+
+    info!("== Creator =============");
+    for creator in list.iter() {
+        info!(" {}", creator);
     }
+    info!("========================");
+    info!("");
+}
+
+/// Find the platform, creating it unless only known creators are processed
+pub fn resolve_platform(
+    manager: &PostArchiverManager<Connection>,
+    config: &Config,
+) -> Result<PlatformId, Box<dyn Error>> {
+    let name = config.platform_name();
+    if let Some(platform) = manager.find_platform(name)? {
+        info!("Platform found: {}", style(name).bold());
+        return Ok(platform);
+    }
+
+    if config.skip_creator_sync() && !config.force_platform_creation() {
+        error!(
+            "Platform {} is not in the archive, no creator is known",
+            name
+        );
+        error!("Sync without --skip-creator-sync, or use --force-platform-creation");
+        return Err("platform not found".into());
+    }
+
+    let platform = manager.import_platform(name.to_string())?;
+    info!("Platform created: {}", style(name).bold());
+    Ok(platform)
 }
 
 pub fn sync_creators(
     manager: &mut PostArchiverManager<Connection>,
-    creators: Vec<(String, PathBuf)>,
+    config: &Config,
+    creators: Creators,
     platform: PlatformId,
-) -> Result<Vec<(AuthorId, PathBuf)>, Box<dyn Error>> {
+) -> Result<Authors, Box<dyn Error>> {
     let mut list = vec![];
     let manager = manager.transaction()?;
 
-    for (creator, path) in creators {
+    for (creator, paths, creator_config) in creators {
+        let name = config.creator_name(&creator);
         let author = match manager.find_author_by_alias(&creator, platform)? {
             Some(id) => Ok(id),
-            None => UnsyncAuthor::new(creator.to_string())
+            None if config.no_alias() => match find_author_by_name(&manager, name)? {
+                Some(id) => Ok(id),
+                None => UnsyncAuthor::new(name.to_string()).sync(&manager),
+            },
+            None => UnsyncAuthor::new(name.to_string())
                 .aliases(vec![UnsyncAlias::new(platform, creator.clone())
-                    .link(format!("https://{}.fanbox.cc/", creator))])
+                    .link(config.alias_link(&creator, config.platform_name()))])
                 .sync(&manager),
         }?;
 
-        list.push((author, path));
+        list.push((author, creator, paths, creator_config));
     }
 
     manager.commit()?;
     Ok(list)
 }
+
+/// Without aliases, authors can only be matched by their name
+fn find_author_by_name<C: PostArchiverConnection>(
+    manager: &PostArchiverManager<C>,
+    name: &str,
+) -> Result<Option<AuthorId>, rusqlite::Error> {
+    manager
+        .conn()
+        .query_row("SELECT id FROM authors WHERE name = ?", [name], |row| {
+            row.get(0)
+        })
+        .optional()
+}
+
+pub fn find_creators(
+    manager: &PostArchiverManager<Connection>,
+    creators: Creators,
+    platform: PlatformId,
+) -> Result<Authors, Box<dyn Error>> {
+    let mut list = vec![];
+
+    for (creator, paths, creator_config) in creators {
+        match manager.find_author_by_alias(&creator, platform)? {
+            Some(author) => list.push((author, creator, paths, creator_config)),
+            None => warn!(" {} is not in the archive, skipping", creator),
+        }
+    }
+
+    Ok(list)
+}
+
+pub fn export_creators_csv(config: &Config, path: &Path) -> Result<usize, Box<dyn Error>> {
+    let database = config.output().join(DATABASE_NAME);
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT authors.id, authors.name, platforms.name, author_aliases.source, author_aliases.link
+         FROM author_aliases
+         JOIN authors ON authors.id = author_aliases.target
+         LEFT JOIN platforms ON platforms.id = author_aliases.platform
+         ORDER BY authors.id",
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["id", "name", "platform", "source_id", "link"])?;
+
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let record: (u32, String, Option<String>, String, Option<String>) = (
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        );
+        writer.serialize(record)?;
+        count += 1;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
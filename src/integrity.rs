@@ -0,0 +1,82 @@
+use std::{error::Error, io};
+
+use clap::ValueEnum;
+
+use console::style;
+use log::{info, warn};
+use post_archiver::{utils::DATABASE_NAME, Post, PostId};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    config::{Config, HashAlgorithm},
+    post::file::{hash_compressed_file, hash_file},
+};
+
+/// Verify every archived file that has a hash extra field,
+/// with the `hash_algorithm` of the file or sha256.
+/// The compressed files are hashed by their decompressed content, as their source was.
+///
+/// Returns `false` if any file is mismatched, missing or can't be read.
+pub fn check_integrity(config: &Config) -> Result<bool, Box<dyn Error>> {
+    let database = config.output().join(DATABASE_NAME);
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT post, filename, algorithm, json_extract(extra, '$.' || algorithm),
+         json_extract(extra, '$.original_mime') IS NOT NULL FROM (
+             SELECT post, filename, extra,
+             COALESCE(json_extract(extra, '$.hash_algorithm'), 'sha256') AS algorithm
+             FROM file_metas
+         ) WHERE json_extract(extra, '$.' || algorithm) IS NOT NULL",
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let (mut ok, mut mismatch, mut missing, mut errors) = (0, 0, 0, 0);
+    while let Some(row) = rows.next()? {
+        let post: PostId = row.get(0)?;
+        let filename: String = row.get(1)?;
+        let algorithm: String = row.get(2)?;
+        let expected: String = row.get(3)?;
+        let compressed: bool = row.get(4)?;
+
+        let path = config.output().join(Post::directory(post)).join(&filename);
+        let Ok(algorithm) = HashAlgorithm::from_str(&algorithm, true) else {
+            warn!(" unknown hash algorithm {}: {}", algorithm, path.display());
+            continue;
+        };
+
+        let digest = match compressed {
+            true => hash_compressed_file(&path, algorithm),
+            false => hash_file(&path, algorithm),
+        };
+        let status = match digest {
+            Ok(digest) if digest.eq_ignore_ascii_case(&expected) => {
+                ok += 1;
+                style("OK").green()
+            }
+            Ok(_) => {
+                mismatch += 1;
+                style("MISMATCH").red()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                missing += 1;
+                style("MISSING").yellow()
+            }
+            Err(e) => {
+                errors += 1;
+                warn!(" {}: {}", path.display(), e);
+                style("ERROR").red()
+            }
+        };
+        info!(" {:<8} {}", status, path.display());
+    }
+
+    info!("");
+    info!("{} {}", ok, style("ok").green());
+    info!("{} {}", mismatch, style("mismatch").red());
+    info!("{} {}", missing, style("missing").yellow());
+    info!("{} {}", errors, style("error").red());
+    info!("");
+
+    Ok(mismatch == 0 && missing == 0 && errors == 0)
+}
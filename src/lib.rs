@@ -0,0 +1,351 @@
+//! Sync FanboxDL archives into a PostArchiver archive.
+//!
+//! The binary is a thin wrapper of [`sync_archive`], which runs the creator
+//! discovery and the sync with a parsed [`Config`].
+
+pub mod backup;
+pub mod config;
+pub mod creator;
+pub mod integrity;
+pub mod metrics;
+pub mod post;
+pub mod refresh;
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use backup::backup_database;
+use console::style;
+use creator::{find_creators, get_creators, resolve_platform, sync_creators};
+use log::{debug, error, info, warn};
+use post::{
+    dedup::{load_hashes, load_names},
+    get_posts,
+    index::{write_index, INDEX_DIR, INDEX_FILENAME},
+    sync_posts, Posts,
+};
+use post_archiver::{
+    importer::{post::UnsyncPost, UnsyncContent},
+    manager::PostArchiverManager,
+    utils::DATABASE_NAME,
+    PlatformId,
+};
+use rusqlite::Connection;
+
+pub use config::{Config, TransformMethod};
+pub use creator::Creators;
+pub use post::stats::{MissingFile, ProgressCallback, ProgressCounters, SyncEvent, SyncStats};
+
+/// Find the creators of the inputs and sync them into the output archive,
+/// returning the statistics of all creators.
+///
+/// The callback receives the post and file events, instead of the progress bars.
+pub async fn sync_archive(
+    config: &Config,
+    callback: Option<ProgressCallback>,
+) -> Result<SyncStats, Box<dyn Error>> {
+    let creators = get_creators(config).await?;
+    let stats = archive(config, creators, callback).await?;
+    Ok(SyncStats::total(&stats))
+}
+
+/// Sync the creators into the output archive, or into one archive per creator
+pub async fn archive(
+    config: &Config,
+    creators: Creators,
+    callback: Option<ProgressCallback>,
+) -> Result<Vec<SyncStats>, Box<dyn Error>> {
+    let mut stats = vec![];
+    if config.output_per_creator() {
+        warn!("Output per creator, files can't be deduplicated across creators");
+        for (i, creator) in creators.into_iter().enumerate() {
+            if i > 0 {
+                wait_between_creators(config).await;
+            }
+            let config = config.with_output(config.output().join(&creator.0));
+            stats.extend(archive_creators(&config, vec![creator], callback.clone()).await?);
+        }
+    } else {
+        stats.extend(archive_creators(config, creators, callback).await?);
+    }
+
+    if let Some((path, cache)) = config.hash_cache() {
+        let count = cache.lock().unwrap().save(path)?;
+        debug!(" {} hashes cached in {}", count, path.display());
+    }
+    Ok(stats)
+}
+
+/// Sync the creators and their posts into the archive at the configured output
+async fn archive_creators(
+    config: &Config,
+    creators: Creators,
+    callback: Option<ProgressCallback>,
+) -> Result<Vec<SyncStats>, Box<dyn Error>> {
+    if !config.output().exists() {
+        warn!("Creating output folder");
+        std::fs::create_dir_all(config.output())?;
+    }
+    config.processed().lock().unwrap().clear();
+
+    let (mut manager, creators, platform) = if config.no_database() {
+        warn!("No database, only files are transformed");
+        if let Some(dedup) = config.dedup() {
+            dedup.lock().unwrap().clear();
+        }
+        (None, creators, PlatformId::new(0))
+    } else {
+        validate_output(config.output())?;
+
+        if config.keep_database_backup() {
+            backup_database(config.output(), config.max_backups())?;
+        }
+
+        info!("Connecting to PostArchiver");
+        let mut manager = PostArchiverManager::open_or_create(config.output())?;
+
+        configure_database(&manager, config)?;
+
+        if let Some(dedup) = config.dedup() {
+            *dedup.lock().unwrap() =
+                load_hashes(&manager, config.output(), config.hash_algorithm())?;
+        }
+
+        let platform = resolve_platform(&manager, config)?;
+
+        let authors = if config.skip_creator_sync() {
+            info!("Finding Creator List");
+            find_creators(&manager, creators, platform)?
+        } else {
+            info!("Syncing Creator List");
+            sync_creators(&mut manager, config, creators, platform)?
+        };
+        let creators = authors
+            .into_iter()
+            .map(|(_, creator, paths, creator_config)| (creator, paths, creator_config))
+            .collect();
+
+        (Some(manager), creators, platform)
+    };
+
+    if let Some(names) = config.names() {
+        info!("Indexing archived filenames");
+        *names.lock().unwrap() = load_names(config.output())?;
+    }
+
+    info!("Resolve Creators Post");
+    let resolve = |(creator, paths, creator_config): (String, Vec<PathBuf>, _)| {
+        let config = match creator_config {
+            Some(creator_config) => config.merge(&creator_config),
+            None => config.clone(),
+        };
+
+        tokio::spawn(async move {
+            let posts = get_posts(&config, &creator, paths.clone(), platform)
+                .await
+                .map_err(|e| e.to_string());
+            (config, creator, paths, posts)
+        })
+    };
+
+    // resolve only a few creators ahead, their extracted zips are kept until synced,
+    // and at most `concurrent_creators - 1` are read while one syncs
+    let mut creators = creators.into_iter();
+    let mut resolving: VecDeque<_> = creators
+        .by_ref()
+        .take(config.concurrent_creators())
+        .map(resolve)
+        .collect();
+
+    let mut list = vec![];
+    while let Some(task) = resolving.pop_front() {
+        if !list.is_empty() {
+            wait_between_creators(config).await;
+        }
+        let (config, creator, paths, posts) = task.await?;
+        let stats = archive_creator(
+            manager.as_mut(),
+            &config,
+            &creator,
+            &paths,
+            posts,
+            callback.clone(),
+        )
+        .await?;
+        list.push(stats);
+        resolving.extend(creators.next().map(resolve));
+    }
+
+    Ok(list)
+}
+
+async fn wait_between_creators(config: &Config) {
+    if let Some(wait) = config.wait_between_creators() {
+        debug!("waiting {} ms before next creator", wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Sync the resolved posts of a creator
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(creator = %creator))
+)]
+async fn archive_creator(
+    manager: Option<&mut PostArchiverManager<Connection>>,
+    config: &Config,
+    creator: &str,
+    paths: &[PathBuf],
+    posts: Result<Posts, String>,
+    callback: Option<ProgressCallback>,
+) -> Result<SyncStats, Box<dyn Error>> {
+    for path in paths.iter() {
+        info!("* {}", style(path.display()).bold());
+    }
+    let Posts {
+        posts,
+        skipped,
+        extracted,
+    } = posts?;
+    let files = posts
+        .iter()
+        .flat_map(|post| &post.content)
+        .filter(|content| matches!(content, UnsyncContent::File(_)))
+        .count();
+    debug!("{} posts, {} files", posts.len(), files);
+    info!("");
+
+    let mut stats = SyncStats::new(creator.to_string());
+    stats.posts_total = posts.len() + skipped;
+    stats.posts_skipped = skipped;
+
+    if !posts.is_empty() {
+        info!("{} posts found", style(posts.len()).bold());
+        if config.check_disk_space() && config.transform() == TransformMethod::Copy {
+            check_disk_space(config.output(), &posts)?;
+        }
+        info!("syncing");
+        let index_dir = match (&manager, config.output_per_creator()) {
+            (Some(_), true) => config.output().clone(),
+            (Some(_), false) => config.output().join(INDEX_DIR).join(creator),
+            (None, _) => config.output().join(creator),
+        };
+        let index_path = index_dir.join(INDEX_FILENAME);
+        let index = match manager {
+            Some(manager) => sync_posts(Some(manager), config, posts, &mut stats, callback).await?,
+            // without post ids, files are grouped by creator
+            None => {
+                let config = config.with_output(config.output().join(creator));
+                sync_posts(None, &config, posts, &mut stats, callback).await?
+            }
+        };
+
+        if config.create_index() {
+            write_index(&index_path, &index)?;
+            info!("index written to {}", index_path.display());
+        }
+    }
+    drop(extracted);
+
+    info!("");
+    Ok(stats)
+}
+
+fn configure_database(
+    manager: &PostArchiverManager<Connection>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(mode) = config.sqlite_journal_mode() {
+        debug!(" journal mode: {}", mode.as_str());
+        manager
+            .conn()
+            .pragma_update(None, "journal_mode", mode.as_str())?;
+    }
+
+    let cache_size = config.sqlite_cache_size();
+    debug!(" cache size: {}", cache_size);
+    manager
+        .conn()
+        .pragma_update(None, "cache_size", cache_size)?;
+    Ok(())
+}
+
+/// The output can't hold the post files of a creator, in bytes
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub need: u64,
+    pub have: u64,
+}
+
+impl Display for InsufficientSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let gb = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+        write!(
+            f,
+            "Insufficient disk space: need {:.2}gb, have {:.2}gb",
+            gb(self.need),
+            gb(self.have)
+        )
+    }
+}
+
+impl Error for InsufficientSpace {}
+
+/// Fail if the output can't hold the post files with a 10% margin
+fn check_disk_space(output: &Path, posts: &[UnsyncPost<PathBuf>]) -> Result<(), Box<dyn Error>> {
+    let need: u64 = posts
+        .iter()
+        .flat_map(|post| post.content.iter())
+        .filter_map(|content| match content {
+            UnsyncContent::File(file) => std::fs::metadata(&file.data).ok(),
+            UnsyncContent::Text(_) => None,
+        })
+        .map(|metadata| metadata.len())
+        .sum();
+    let have = fs4::available_space(output)?;
+
+    let gb = |bytes: u64| bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+    debug!(
+        " disk space: need {:.2}gb, have {:.2}gb",
+        gb(need),
+        gb(have)
+    );
+    if have < need + need / 10 {
+        return Err(InsufficientSpace { need, have }.into());
+    }
+    Ok(())
+}
+
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Check the output is empty or an existing PostArchiver archive
+fn validate_output(output: &Path) -> Result<(), Box<dyn Error>> {
+    let database = output.join(DATABASE_NAME);
+    if database.is_file() {
+        let mut magic = [0; 16];
+        let valid = File::open(&database)?.read_exact(&mut magic).is_ok() && &magic == SQLITE_MAGIC;
+        if !valid {
+            error!("{} is not a SQLite database", database.display());
+            error!("Move it away to create a new archive, or restore it from a backup");
+            return Err("invalid archive database".into());
+        }
+        return Ok(());
+    }
+
+    let is_empty = std::fs::read_dir(output)?
+        .flatten()
+        .all(|entry| entry.file_name().to_string_lossy().starts_with('.'));
+    if !is_empty {
+        error!("{} is not a PostArchiver archive", output.display());
+        error!("Choose an empty folder or an existing archive as the output");
+        return Err("invalid archive output".into());
+    }
+
+    Ok(())
+}
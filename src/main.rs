@@ -1,20 +1,32 @@
-mod config;
-mod creator;
-mod post;
-
 use std::error::Error;
 
-use config::Config;
+use clap::{error::ErrorKind, CommandFactory};
 use console::style;
-use creator::{display_creators, get_creators, sync_creators};
-use log::{info, warn};
-use post::{get_posts, sync_posts};
-use post_archiver::{manager::PostArchiverManager, utils::VERSION};
+use fanbox_dl_archive::{
+    archive,
+    creator::{display_creators, export_creators_csv, get_creators, Creators},
+    integrity::check_integrity,
+    metrics::serve_metrics,
+    post::{
+        self, get_posts,
+        stats::{write_missing_files, write_statistics},
+        Posts,
+    },
+    refresh::refresh_metadata,
+    Config, InsufficientSpace,
+};
+use log::{error, info, warn};
+use post_archiver::{importer::UnsyncContent, utils::VERSION, PlatformId};
+use serde_json::json;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::parse();
-    config.init_logger();
+    let config = Config::try_parse().unwrap_or_else(|e| e.exit());
+    if let Err(e) = config.init_logger() {
+        Config::command().error(ErrorKind::Io, e).exit();
+    }
+    #[cfg(unix)]
+    post::stats::report_progress_on_signal(config.progress().clone())?;
 
     info!("{}", style("Fanbox DL Archive").bold().dim());
     info!("");
@@ -23,45 +35,128 @@ async fn main() -> Result<(), Box<dyn Error>> {
         "PostArchiver version: {}",
         style(format!("v{}", VERSION)).bold()
     );
+    check_post_archiver_version();
+    if let Some(address) = config.metrics_address() {
+        serve_metrics(address, config.progress().clone()).await?;
+    }
     info!("Overwrite: {}", style(config.overwrite()).bold());
     info!("Transform: {}", style(config.transform()).bold());
-    info!("Input: {}", style(config.input().display()).bold());
+    for input in config.input() {
+        info!("Input: {}", style(input.display()).bold());
+    }
+    if config.input_from_stdin() {
+        info!("Input: {}", style("stdin").bold());
+    }
     info!("Output: {}", style(config.output().display()).bold());
     info!("==================================");
 
-    if !config.output().exists() {
-        warn!("Creating output folder");
-        std::fs::create_dir_all(config.output())?;
+    if let Some(path) = config.export_creators_csv() {
+        info!("Exporting creators to {}", style(path.display()).bold());
+        let count = export_creators_csv(&config, path)?;
+        info!("{} aliases exported", style(count).bold());
+        return Ok(());
     }
 
-    info!("Connecting to PostArchiver");
-    let mut manager = PostArchiverManager::open_or_create(config.output())?;
+    if config.check_integrity() {
+        info!("Checking archive integrity");
+        if !check_integrity(&config)? {
+            error!("Archive integrity check failed");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if config.refresh_metadata() {
+        info!("Refreshing file metadata");
+        refresh_metadata(&config)?;
+        return Ok(());
+    }
 
     info!("Loading Creator List");
     let creators = get_creators(&config).await?;
+    if creators.is_empty() && config.error_on_empty_input() {
+        error!("No creators found in input directory");
+        std::process::exit(5);
+    }
     display_creators(&creators);
 
-    let platform = manager.import_platform("fanbox-dl".to_string())?;
-
-    info!("Syncing Creator List");
-    let authors = sync_creators(&mut manager, creators, platform)?;
-
-    info!("Resolve Creators Post");
-    for (_, path) in authors {
-        info!("* {}", style(&path.display()).bold());
-        info!("resolving");
-        let posts = get_posts(path, platform).await?;
-        info!("");
+    if config.list_posts() {
+        list_posts(&config, creators).await?;
+        return Ok(());
+    }
 
-        if !posts.is_empty() {
-            info!("{} posts found", style(posts.len()).bold());
-            info!("syncing");
-            sync_posts(&mut manager, &config, posts).await?;
+    let stats = match archive(&config, creators, None).await {
+        Ok(stats) => stats,
+        Err(e) if e.is::<InsufficientSpace>() => {
+            error!("{}", e);
+            std::process::exit(4);
         }
+        Err(e) => return Err(e),
+    };
 
-        info!("");
+    if let Some(path) = config.creator_statistics_file() {
+        write_statistics(path, &stats)?;
+        info!("Statistics written to {}", style(path.display()).bold());
+    }
+
+    let missing = stats.iter().any(|stats| !stats.files_missing.is_empty());
+    if let Some(path) = config.export_missing_files().filter(|_| missing) {
+        let count = write_missing_files(path, &stats)?;
+        warn!(
+            "{} missing files written to {}",
+            count,
+            style(path.display()).bold()
+        );
     }
 
     info!("All done!");
     Ok(())
 }
+
+/// Print a JSON line per post of the creators
+async fn list_posts(config: &Config, creators: Creators) -> Result<(), Box<dyn Error>> {
+    for (creator, paths, creator_config) in creators {
+        let config = match creator_config {
+            Some(creator_config) => config.merge(&creator_config),
+            None => config.clone(),
+        };
+
+        let Posts { posts, .. } = get_posts(&config, &creator, paths, PlatformId::new(0)).await?;
+        for post in posts {
+            let file_count = post
+                .content
+                .iter()
+                .filter(|content| matches!(content, UnsyncContent::File(_)))
+                .count();
+            let line = json!({
+                "creator": creator,
+                "title": post.title,
+                "date": post.published.map(|date| date.to_rfc3339()),
+                "file_count": file_count,
+            });
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Oldest PostArchiver whose archive layout this tool writes
+const MIN_POST_ARCHIVER_VERSION: (u32, u32, u32) = (0, 5, 0);
+
+/// Warn when the linked PostArchiver is older than the supported one
+fn check_post_archiver_version() {
+    let mut parts = VERSION
+        .split(['.', '-', '+'])
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let mut next = || parts.next().unwrap_or(0);
+    let version = (next(), next(), next());
+
+    if version < MIN_POST_ARCHIVER_VERSION {
+        let (major, minor, patch) = MIN_POST_ARCHIVER_VERSION;
+        error!(
+            "PostArchiver v{} is not supported, v{}.{}.{} or newer is required",
+            VERSION, major, minor, patch
+        );
+        error!("Rebuild with a newer post-archiver, and back up the archive before syncing");
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
+
+use console::style;
+use http_body_util::Full;
+use hyper::{body::Bytes, server::conn::http1, service::service_fn, Request, Response};
+use hyper_util::rt::TokioIo;
+use log::{debug, info};
+use prometheus::{Encoder, IntGauge, Registry, TextEncoder};
+use tokio::net::TcpListener;
+
+use crate::post::stats::ProgressCounters;
+
+/// Wait after a failed accept
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A counter of the run progress
+type Counter = fn(&ProgressCounters) -> &AtomicU64;
+
+/// Gauges of the run progress, set from the counters on every scrape
+struct Metrics {
+    registry: Registry,
+    gauges: Vec<(IntGauge, Counter)>,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, Box<dyn Error>> {
+        let metrics: [(&str, &str, Counter); 5] = [
+            ("fanboxdl_posts_total", "Posts found", |p| &p.posts_total),
+            ("fanboxdl_posts_synced", "Posts synced", |p| &p.posts),
+            ("fanboxdl_files_total", "Files found", |p| &p.files_total),
+            ("fanboxdl_files_synced", "Files transferred", |p| &p.files),
+            ("fanboxdl_bytes_transferred", "Bytes transferred", |p| {
+                &p.bytes
+            }),
+        ];
+
+        let registry = Registry::new();
+        let mut gauges = vec![];
+        for (name, help, read) in metrics {
+            let gauge = IntGauge::new(name, help)?;
+            registry.register(Box::new(gauge.clone()))?;
+            gauges.push((gauge, read));
+        }
+        Ok(Self { registry, gauges })
+    }
+
+    fn render(&self, progress: &ProgressCounters) -> Vec<u8> {
+        for (gauge, read) in self.gauges.iter() {
+            gauge.set(read(progress).load(Relaxed) as i64);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        buffer
+    }
+}
+
+/// Serve the progress as Prometheus metrics on the address
+pub async fn serve_metrics(
+    address: SocketAddr,
+    progress: Arc<ProgressCounters>,
+) -> Result<(), Box<dyn Error>> {
+    let metrics = Arc::new(Metrics::new()?);
+    let listener = TcpListener::bind(address).await?;
+    let address = format!("http://{}/metrics", listener.local_addr()?);
+    info!("Metrics: {}", style(address).bold());
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // e.g. out of file descriptors, retrying at once would spin
+                    debug!(" metrics accept: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let (metrics, progress) = (metrics.clone(), progress.clone());
+            let service = service_fn(move |_: Request<_>| {
+                let body = metrics.render(&progress);
+                async move { Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body)))) }
+            });
+
+            tokio::spawn(async move {
+                let connection =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                if let Err(e) = connection.await {
+                    debug!(" metrics connection: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
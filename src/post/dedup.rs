@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::debug;
+use post_archiver::{
+    importer::{post::UnsyncPost, UnsyncContent},
+    manager::{PostArchiverConnection, PostArchiverManager},
+    Post, PostId,
+};
+use tokio::fs;
+
+use super::{index::INDEX_FILENAME, thumbnail::THUMBNAIL_FILENAME};
+use crate::config::HashAlgorithm;
+
+/// `(target, source)` pairs
+type Files = Vec<(PathBuf, PathBuf)>;
+
+/// Archived file of each hash, shared by all creators of an archive
+pub type DedupIndex = Arc<Mutex<HashMap<String, PathBuf>>>;
+
+/// Load the files with a stored hash of the algorithm,
+/// the files without `hash_algorithm` are hashed by sha256
+pub fn load_hashes<C: PostArchiverConnection>(
+    manager: &PostArchiverManager<C>,
+    output: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<HashMap<String, PathBuf>, Box<dyn Error>> {
+    let conn = manager.conn();
+    let mut stmt = conn.prepare(
+        "SELECT post, filename, json_extract(extra, '$.' || ?1) FROM file_metas
+         WHERE json_extract(extra, '$.' || ?1) IS NOT NULL
+         AND COALESCE(json_extract(extra, '$.hash_algorithm'), 'sha256') = ?1",
+    )?;
+    let hashes = stmt
+        .query_map([algorithm.as_str()], |row| {
+            let post: PostId = row.get(0)?;
+            let filename: String = row.get(1)?;
+            let path = output.join(Post::directory(post)).join(filename);
+            Ok((row.get(2)?, path))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(hashes)
+}
+
+/// Index the archived files by filename, the first found is kept.
+/// The generated thumbnails and indexes are not post files, and are left out
+pub fn load_names(output: &Path) -> io::Result<HashMap<String, PathBuf>> {
+    fn walk(dir: &Path, names: &mut HashMap<String, PathBuf>) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let filename = entry.file_name().to_string_lossy().to_string();
+            if filename.starts_with('.')
+                || [THUMBNAIL_FILENAME, INDEX_FILENAME].contains(&&*filename)
+            {
+                continue;
+            }
+            let filetype = entry.file_type()?;
+            if filetype.is_dir() {
+                walk(&entry.path(), names)?;
+            } else if filetype.is_file() {
+                names.entry(filename).or_insert(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    let mut names = HashMap::new();
+    // the files at the root are the database and its backups
+    for entry in std::fs::read_dir(output)?.flatten() {
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.file_type()?.is_dir() {
+            walk(&entry.path(), &mut names)?;
+        }
+    }
+    Ok(names)
+}
+
+/// Split out the files with an already archived filename, as `(target, existing)`
+pub fn split_by_name(index: &DedupIndex, files: Files, size_match: bool) -> (Files, Files) {
+    let size = |path: &Path| std::fs::metadata(path).map(|metadata| metadata.len()).ok();
+
+    let mut index = index.lock().unwrap();
+    let mut unique = vec![];
+    let mut duplicates = vec![];
+    for (target, source) in files {
+        let Some(filename) = target
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            unique.push((target, source));
+            continue;
+        };
+
+        match index.get(&filename) {
+            Some(existing)
+                if *existing != target && (!size_match || size(existing) == size(&source)) =>
+            {
+                debug!(" linked: {} to {}", target.display(), existing.display());
+                duplicates.push((target, existing.clone()));
+            }
+            Some(_) => unique.push((target, source)),
+            None => {
+                index.insert(filename, target.clone());
+                unique.push((target, source));
+            }
+        }
+    }
+    (unique, duplicates)
+}
+
+/// Hash of the post files by their path in the post folder
+pub fn post_hashes(
+    post: &UnsyncPost<PathBuf>,
+    algorithm: HashAlgorithm,
+) -> HashMap<PathBuf, String> {
+    post.content
+        .iter()
+        .filter_map(|content| match content {
+            UnsyncContent::File(file) => {
+                let hash = file.extra.get(algorithm.as_str())?.as_str()?;
+                Some((PathBuf::from(&file.filename), hash.to_string()))
+            }
+            UnsyncContent::Text(_) => None,
+        })
+        .collect()
+}
+
+/// Split out the files with an already archived hash, as `(target, existing)`,
+/// the targets are looked up by their path in `dir`
+pub fn split_duplicates(
+    index: &DedupIndex,
+    hashes: &HashMap<PathBuf, String>,
+    dir: &Path,
+    files: Files,
+) -> (Files, Files) {
+    let mut index = index.lock().unwrap();
+    let mut unique = vec![];
+    let mut duplicates = vec![];
+    for (target, source) in files {
+        let hash = target
+            .strip_prefix(dir)
+            .ok()
+            .and_then(|filename| hashes.get(filename));
+        let Some(hash) = hash else {
+            unique.push((target, source));
+            continue;
+        };
+
+        match index.get(hash) {
+            Some(existing) if *existing != target => {
+                debug!(
+                    " deduped: {} matches {}",
+                    target.display(),
+                    existing.display()
+                );
+                duplicates.push((target, existing.clone()));
+            }
+            _ => {
+                index.insert(hash.clone(), target.clone());
+                unique.push((target, source));
+            }
+        }
+    }
+    (unique, duplicates)
+}
+
+/// Hard link the target to the existing file, or copy across filesystems
+pub async fn link_duplicate(target: &Path, existing: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if fs::symlink_metadata(target).await.is_ok() {
+        fs::remove_file(target).await?;
+    }
+    match fs::hard_link(existing, target).await {
+        Ok(()) => Ok(()),
+        Err(_) => fs::copy(existing, target).await.map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_filename_in_subfolder_is_not_a_duplicate() {
+        let dir = Path::new("out/post");
+        let hashes = HashMap::from([
+            (PathBuf::from("a.txt"), "AAAA".to_string()),
+            (PathBuf::from("sub/a.txt"), "BBBB".to_string()),
+        ]);
+        let files = vec![
+            (dir.join("a.txt"), PathBuf::from("in/a.txt")),
+            (dir.join("sub/a.txt"), PathBuf::from("in/sub/a.txt")),
+        ];
+
+        let index = DedupIndex::default();
+        let (unique, duplicates) = split_duplicates(&index, &hashes, dir, files);
+        assert_eq!(unique.len(), 2);
+        assert!(duplicates.is_empty());
+    }
+}
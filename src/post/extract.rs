@@ -0,0 +1,74 @@
+use std::{
+    error::Error,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use tempfile::TempDir;
+use tokio::fs;
+use zip::ZipArchive;
+
+use crate::config::Config;
+
+pub fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Find the zip archive of a creator path.
+///
+/// Either the path itself is a zip, or it is a directory containing only one zip.
+pub async fn find_zip(path: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    if fs::metadata(path).await?.is_file() {
+        return Ok(is_zip(path).then(|| path.to_path_buf()));
+    }
+
+    let mut entries = vec![];
+    let mut entrys = fs::read_dir(path).await?;
+    while let Ok(Some(entry)) = entrys.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        entries.push((entry.path(), entry.file_type().await?));
+    }
+
+    Ok(match entries.as_slice() {
+        [(zip, filetype)] if filetype.is_file() && is_zip(zip) => Some(zip.clone()),
+        _ => None,
+    })
+}
+
+/// Date of the zip by its name, e.g. `2024-01-15.zip`, or by its modified time
+pub async fn zip_date(zip: &Path) -> Option<DateTime<Utc>> {
+    let name = zip.file_stem()?.to_string_lossy().to_string();
+    let date = name
+        .get(..10)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+    match date {
+        Some(date) => Some(date.and_time(NaiveTime::MIN).and_utc()),
+        None => Some(fs::metadata(zip).await.ok()?.modified().ok()?.into()),
+    }
+}
+
+/// Extract the zip into a temporary directory, which is removed on drop.
+///
+/// The directory lives in the output, so moving files out of it stays on the same filesystem.
+/// `--list-posts` only reads the files, and extracts into the system temporary directory.
+pub async fn extract_zip(config: &Config, zip: PathBuf) -> Result<TempDir, Box<dyn Error>> {
+    let output = (!config.list_posts()).then(|| config.output().clone());
+    let dir = tokio::task::spawn_blocking(move || -> Result<_, Box<dyn Error + Send + Sync>> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".extract-");
+        let dir = match output {
+            Some(output) => builder.tempdir_in(output)?,
+            None => builder.tempdir()?,
+        };
+        ZipArchive::new(File::open(&zip)?)?.extract(dir.path())?;
+        Ok(dir)
+    })
+    .await?
+    .map_err(|e| -> Box<dyn Error> { e })?;
+
+    Ok(dir)
+}
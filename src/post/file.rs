@@ -1,29 +1,35 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
 
 use mime_guess::MimeGuess;
 use post_archiver::importer::file_meta::UnsyncFileMeta;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::config::HashAlgorithm;
 
 pub trait FanboxDLFileMeta
 where
     Self: Sized,
 {
-    fn from_path(path: PathBuf) -> Self;
+    fn from_path(path: PathBuf, provider: &dyn ExtraMetadataProvider) -> Self;
 }
 
 impl FanboxDLFileMeta for UnsyncFileMeta<PathBuf> {
-    fn from_path(path: PathBuf) -> Self {
+    fn from_path(path: PathBuf, provider: &dyn ExtraMetadataProvider) -> Self {
         let filename = path.file_name().unwrap().to_string_lossy().to_string();
         let mime = MimeGuess::from_path(&path)
             .first_or_octet_stream()
             .to_string();
 
-        let mut extra: HashMap<String, serde_json::Value> = Default::default();
-
-        if let Ok(size) = imagesize::size(&path) {
-            extra.insert("width".to_string(), json!(size.width));
-            extra.insert("height".to_string(), json!(size.height));
-        }
+        let extra = provider.extract(&path, &mime);
 
         Self {
             filename,
@@ -33,3 +39,481 @@ impl FanboxDLFileMeta for UnsyncFileMeta<PathBuf> {
         }
     }
 }
+
+/// Extract the extra metadata stored with a file
+pub trait ExtraMetadataProvider: Send + Sync {
+    fn extract(&self, path: &Path, mime: &str) -> HashMap<String, Value>;
+    /// Keys expected from `extract` for the mime
+    fn keys(&self, mime: &str) -> Vec<&'static str>;
+}
+
+/// Image width and height
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultProvider {
+    /// Cache the image size in a `<file>.dim` sidecar
+    pub dim_cache: bool,
+}
+
+impl DefaultProvider {
+    fn image_size(&self, path: &Path) -> Option<ImageSize> {
+        if !self.dim_cache {
+            return imagesize::size(path).ok().map(ImageSize::from);
+        }
+
+        let mut cache = path.as_os_str().to_owned();
+        cache.push(".dim");
+        let cache = PathBuf::from(cache);
+
+        if let Some(size) = read_dim_cache(path, &cache) {
+            return Some(size);
+        }
+
+        let size = imagesize::size(path).ok().map(ImageSize::from)?;
+        // the source may be read-only
+        if let Ok(json) = serde_json::to_vec(&size) {
+            fs::write(&cache, json).ok();
+        }
+        Some(size)
+    }
+}
+
+impl ExtraMetadataProvider for DefaultProvider {
+    fn extract(&self, path: &Path, _mime: &str) -> HashMap<String, Value> {
+        let mut extra = HashMap::new();
+
+        if let Some(size) = self.image_size(path) {
+            extra.insert("width".to_string(), json!(size.width));
+            extra.insert("height".to_string(), json!(size.height));
+        }
+
+        extra
+    }
+
+    fn keys(&self, mime: &str) -> Vec<&'static str> {
+        match mime.starts_with("image/") {
+            true => vec!["width", "height"],
+            false => vec![],
+        }
+    }
+}
+
+/// The default metadata, with the hash, mp4 duration and pdf pages
+#[derive(Debug, Clone, Default)]
+pub struct ExtendedProvider {
+    pub default: DefaultProvider,
+    pub hash: HashAlgorithm,
+    pub cache: Option<Arc<Mutex<HashCache>>>,
+}
+
+impl ExtendedProvider {
+    /// Hash the file, unless the cache has the same path, modified time and size
+    fn hash(&self, path: &Path) -> io::Result<String> {
+        let Some(cache) = &self.cache else {
+            return hash_file(path, self.hash);
+        };
+
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH);
+        let modified = modified.map_or(0, |modified| modified.as_nanos() as u64);
+        let key = (path.to_path_buf(), modified, metadata.len());
+        if let Some(hash) = cache.lock().unwrap().entries.get(&key) {
+            return Ok(hash.clone());
+        }
+
+        let hash = hash_file(path, self.hash)?;
+        cache.lock().unwrap().entries.insert(key, hash.clone());
+        Ok(hash)
+    }
+}
+
+impl ExtraMetadataProvider for ExtendedProvider {
+    fn extract(&self, path: &Path, mime: &str) -> HashMap<String, Value> {
+        let mut extra = self.default.extract(path, mime);
+
+        if let Ok(hash) = self.hash(path) {
+            extra.insert(self.hash.as_str().to_string(), json!(hash));
+            extra.insert("hash_algorithm".to_string(), json!(self.hash.as_str()));
+        }
+
+        match mime {
+            "video/mp4" | "video/quicktime" => {
+                if let Ok(Some(duration)) = mp4_duration(path) {
+                    extra.insert("duration".to_string(), json!(duration));
+                }
+            }
+            "application/pdf" => {
+                if let Ok(pages) = pdf_pages(path) {
+                    extra.insert("pages".to_string(), json!(pages));
+                }
+            }
+            _ => {}
+        }
+
+        extra
+    }
+
+    fn keys(&self, mime: &str) -> Vec<&'static str> {
+        let mut keys = self.default.keys(mime);
+        keys.push(self.hash.as_str());
+        match mime {
+            "video/mp4" | "video/quicktime" => keys.push("duration"),
+            "application/pdf" => keys.push("pages"),
+            _ => {}
+        }
+        keys
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ImageSize {
+    width: usize,
+    height: usize,
+}
+
+impl From<imagesize::ImageSize> for ImageSize {
+    fn from(size: imagesize::ImageSize) -> Self {
+        Self {
+            width: size.width,
+            height: size.height,
+        }
+    }
+}
+
+/// Whether the file is a `--dim-cache` sidecar, of an image next to it
+pub fn is_dim_cache(path: &Path) -> bool {
+    let is_dim = path.extension().is_some_and(|extension| extension == "dim");
+    is_dim
+        && path.with_extension("").is_file()
+        && fs::read(path).is_ok_and(|bytes| serde_json::from_slice::<ImageSize>(&bytes).is_ok())
+}
+
+/// Read the cached image size, if the cache is newer than the image
+fn read_dim_cache(path: &Path, cache: &Path) -> Option<ImageSize> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    let cached = fs::metadata(cache)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    if cached <= modified {
+        return None;
+    }
+    serde_json::from_slice(&fs::read(cache).ok()?).ok()
+}
+
+/// Digests of the files by path, modified time and size, kept across runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashCache {
+    algorithm: String,
+    entries: HashMap<(PathBuf, u64, u64), String>,
+}
+
+impl HashCache {
+    /// Load the cache, empty if missing, unreadable or of another algorithm
+    pub fn load(path: &Path, algorithm: HashAlgorithm) -> io::Result<Self> {
+        let cache: Self = match fs::read(path) {
+            Ok(bytes) => postcard::from_bytes(&bytes).unwrap_or_default(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e),
+        };
+        match cache.algorithm == algorithm.as_str() {
+            true => Ok(cache),
+            false => Ok(Self {
+                algorithm: algorithm.as_str().to_string(),
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Save the cache, dropping the files which no longer exist
+    pub fn save(&mut self, path: &Path) -> io::Result<usize> {
+        self.entries.retain(|(file, _, _), _| file.exists());
+        let bytes = postcard::to_stdvec(self).map_err(io::Error::other)?;
+        fs::write(path, bytes)?;
+        Ok(self.entries.len())
+    }
+}
+
+/// Hex digest of the file
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    hash_reader(&mut File::open(path)?, algorithm)
+}
+
+/// Hex digest of the decompressed content of a `--compress-output` file
+pub fn hash_compressed_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    hash_reader(&mut zstd::Decoder::new(File::open(path)?)?, algorithm)
+}
+
+fn hash_reader(file: &mut impl Read, algorithm: HashAlgorithm) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            io::copy(file, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        HashAlgorithm::Sha512 => {
+            let mut hasher = Sha512::new();
+            io::copy(file, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(file, &mut hasher)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+    }
+}
+
+/// Duration in seconds from the `moov/mvhd` box
+fn mp4_duration(path: &Path) -> io::Result<Option<f64>> {
+    let mut file = File::open(path)?;
+    let Some(moov) = find_box(&mut file, *b"moov", u64::MAX)? else {
+        return Ok(None);
+    };
+    if find_box(&mut file, *b"mvhd", moov)?.is_none() {
+        return Ok(None);
+    }
+
+    let mut version = [0; 4];
+    file.read_exact(&mut version)?;
+    let (timescale, duration) = match version[0] {
+        1 => {
+            let mut header = [0; 28];
+            file.read_exact(&mut header)?;
+            let timescale = u32::from_be_bytes(header[16..20].try_into().unwrap());
+            let duration = u64::from_be_bytes(header[20..28].try_into().unwrap());
+            (timescale, duration)
+        }
+        _ => {
+            let mut header = [0; 16];
+            file.read_exact(&mut header)?;
+            let timescale = u32::from_be_bytes(header[8..12].try_into().unwrap());
+            let duration = u32::from_be_bytes(header[12..16].try_into().unwrap()) as u64;
+            (timescale, duration)
+        }
+    };
+
+    Ok((timescale > 0).then(|| duration as f64 / timescale as f64))
+}
+
+/// Seek into the payload of the box, returns the end of the box
+fn find_box(file: &mut File, name: [u8; 4], end: u64) -> io::Result<Option<u64>> {
+    let mut header = [0; 8];
+    loop {
+        let start = file.stream_position()?;
+        if start
+            .checked_add(8)
+            .is_none_or(|header_end| header_end > end)
+            || file.read_exact(&mut header).is_err()
+        {
+            return Ok(None);
+        }
+
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) as u64 {
+            1 => {
+                let mut size = [0; 8];
+                file.read_exact(&mut size)?;
+                u64::from_be_bytes(size)
+            }
+            0 => return Ok((header[4..] == name).then_some(end)),
+            size => size,
+        };
+        // the sizes are read from the file, a crafted one may overflow
+        let Some(box_end) = start.checked_add(size).filter(|_| size >= 8) else {
+            return Ok(None);
+        };
+
+        if header[4..] == name {
+            return Ok(Some(box_end));
+        }
+        file.seek(SeekFrom::Start(box_end))?;
+    }
+}
+
+/// Count the page objects, compressed object streams are not counted
+fn pdf_pages(path: &Path) -> io::Result<usize> {
+    let data = fs::read(path)?;
+    let pages = data
+        .windows(12)
+        .filter(|window| window.starts_with(b"/Type") && is_page(&window[5..]))
+        .count();
+    Ok(pages)
+}
+
+fn is_page(rest: &[u8]) -> bool {
+    let rest = match rest.first() {
+        Some(b' ') => &rest[1..],
+        _ => rest,
+    };
+    rest.starts_with(b"/Page") && rest.get(5) != Some(&b's')
+}
+
+/// Name and path limits of the output filesystem
+#[derive(Debug, Clone, Copy)]
+pub struct OutputLimits {
+    pub name_max: usize,
+    pub path_max: usize,
+    /// FAT, exFAT or NTFS, which reject the characters replaced by `sanitize_filename`
+    pub windows_names: bool,
+}
+
+impl OutputLimits {
+    pub fn detect(output: &Path) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            use nix::sys::{statfs, statvfs};
+
+            // the output may not be created yet
+            let path = output
+                .ancestors()
+                .find(|path| path.exists())
+                .unwrap_or(output);
+            let name_max = statvfs::statvfs(path)
+                .map(|stat| stat.name_max() as usize)
+                .unwrap_or(255);
+            let windows_names = statfs::statfs(path).is_ok_and(|stat| {
+                const EXFAT_SUPER_MAGIC: statfs::FsType = statfs::FsType(0x2011_bab0);
+                const NTFS_SB_MAGIC: statfs::FsType = statfs::FsType(0x5346_544e);
+                [statfs::MSDOS_SUPER_MAGIC, EXFAT_SUPER_MAGIC, NTFS_SB_MAGIC]
+                    .contains(&stat.filesystem_type())
+            });
+            Self {
+                name_max,
+                path_max: 4096,
+                windows_names,
+            }
+        }
+        #[cfg(windows)]
+        {
+            let _ = output;
+            Self {
+                name_max: 255,
+                path_max: 260,
+                windows_names: true,
+            }
+        }
+        #[cfg(not(any(target_os = "linux", windows)))]
+        {
+            let _ = output;
+            Self {
+                name_max: 255,
+                path_max: 1024,
+                windows_names: false,
+            }
+        }
+    }
+
+    /// Max bytes of a path component under a parent of `parent` bytes
+    pub fn name_limit(&self, parent: usize) -> usize {
+        self.name_max.min(self.path_max.saturating_sub(parent + 1))
+    }
+
+    /// Whether the components of the `/` separated name are valid
+    pub fn is_valid(&self, name: &str) -> bool {
+        match self.windows_names {
+            true => sanitize_path(name, '_') == name,
+            false => !name.contains('\0'),
+        }
+    }
+}
+
+/// Replace the characters which are illegal on Windows,
+/// and trim the trailing dots and spaces.
+pub fn sanitize_filename(filename: &str, replacement: char) -> String {
+    const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+    let sanitized: String = filename
+        .chars()
+        .map(|c| match ILLEGAL.contains(&c) || c.is_control() {
+            true => replacement,
+            false => c,
+        })
+        .collect();
+
+    let sanitized = sanitized.trim_end_matches(['.', ' ']);
+    match sanitized.is_empty() {
+        true => replacement.to_string(),
+        false => sanitized.to_string(),
+    }
+}
+
+/// Sanitize each component of a `/` separated filename
+pub fn sanitize_path(filename: &str, replacement: char) -> String {
+    filename
+        .split('/')
+        .map(|part| sanitize_filename(part, replacement))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Truncate to at most `max` bytes, at a char boundary
+pub fn truncate_str(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Truncate the last component of a `/` separated path, keeping the folders
+pub fn truncate_path(path: &str, max: usize) -> String {
+    match path.rsplit_once('/') {
+        Some((folders, filename)) => format!("{}/{}", folders, truncate_filename(filename, max)),
+        None => truncate_filename(path, max),
+    }
+}
+
+/// Truncate to at most `max` bytes, preserving the extension
+pub fn truncate_filename(filename: &str, max: usize) -> String {
+    if filename.len() <= max {
+        return filename.to_string();
+    }
+
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) if extension.len() + 1 < max => {
+            let stem = truncate_str(stem, max - extension.len() - 1);
+            format!("{}.{}", stem, extension)
+        }
+        _ => truncate_str(filename, max).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn find_box_with_overflowing_size() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0, 0, 0, 8]).unwrap();
+        file.write_all(b"free").unwrap();
+        file.write_all(&[0, 0, 0, 1]).unwrap();
+        file.write_all(b"skip").unwrap();
+        file.write_all(&u64::MAX.to_be_bytes()).unwrap();
+        file.rewind().unwrap();
+
+        assert_eq!(find_box(&mut file, *b"moov", u64::MAX).unwrap(), None);
+    }
+
+    #[test]
+    fn sanitize_illegal_characters() {
+        assert_eq!(sanitize_filename("a<b>c:d", '_'), "a_b_c_d");
+        assert_eq!(sanitize_filename("a/b\\c\n", '_'), "a_b_c_");
+        assert_eq!(sanitize_filename("name. .", '_'), "name");
+        assert_eq!(sanitize_filename("...", '_'), "_");
+        assert_eq!(sanitize_path("a?/b*", '_'), "a_/b_");
+    }
+
+    #[test]
+    fn truncate_keeps_extension() {
+        assert_eq!(truncate_filename("short.txt", 10), "short.txt");
+        assert_eq!(truncate_filename("abcdefgh.txt", 8), "abcd.txt");
+        assert_eq!(truncate_filename("abcdefgh.longext", 5), "abcde");
+        assert_eq!(truncate_filename("abcdefgh", 4), "abcd");
+        // `あ` is 3 bytes, the cut moves back to a char boundary
+        assert_eq!(truncate_filename("ああ.txt", 8), "あ.txt");
+        assert_eq!(truncate_path("folder/abcdefgh.txt", 8), "folder/abcd.txt");
+    }
+}
@@ -0,0 +1,62 @@
+use std::{error::Error, path::Path};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::config::Config;
+
+pub const IGNORE_FILENAME: &str = ".fanboxdlignore";
+
+/// Files created by the operating system
+const DEFAULT_IGNORES: &[&str] = &["Thumbs.db", ".DS_Store", "desktop.ini"];
+
+/// Gitignore patterns of the input, the creator and `--ignore-file`
+#[derive(Debug, Clone)]
+pub struct Ignore {
+    matchers: Vec<Gitignore>,
+}
+
+impl Ignore {
+    /// Load the patterns for a creator path read from `root`.
+    ///
+    /// The input patterns are relative to the input directory,
+    /// the creator patterns are relative to `root`.
+    pub fn load(config: &Config, path: &Path, root: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut matchers = vec![];
+
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in DEFAULT_IGNORES {
+            builder.add_line(None, pattern)?;
+        }
+        if let Some(file) = config.ignore_file() {
+            if let Some(e) = builder.add(file) {
+                return Err(e.into());
+            }
+        }
+        add_file(&mut builder, &root.join(IGNORE_FILENAME))?;
+        matchers.push(builder.build()?);
+
+        if let Some(input) = path.parent() {
+            let mut builder = GitignoreBuilder::new(input);
+            add_file(&mut builder, &input.join(IGNORE_FILENAME))?;
+            matchers.push(builder.build()?);
+        }
+
+        Ok(Self { matchers })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.matchers
+            .iter()
+            .any(|matcher| matcher.matched(path, is_dir).is_ignore())
+    }
+}
+
+fn add_file(builder: &mut GitignoreBuilder, file: &Path) -> Result<(), Box<dyn Error>> {
+    if !file.is_file() {
+        return Ok(());
+    }
+    match builder.add(file) {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
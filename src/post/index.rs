@@ -0,0 +1,52 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::Config;
+
+pub const INDEX_FILENAME: &str = "index.json";
+/// Folder of the creator indexes in a shared archive, apart from the post folders
+pub const INDEX_DIR: &str = "indexes";
+
+/// A synced post, as listed in the creator index
+#[derive(Debug, Clone, Serialize)]
+pub struct PostIndex {
+    pub title: String,
+    pub date: Option<DateTime<Utc>>,
+    /// Paths relative to the archive root
+    pub files: Vec<PathBuf>,
+}
+
+impl PostIndex {
+    pub fn new(
+        config: &Config,
+        title: String,
+        date: Option<DateTime<Utc>>,
+        files: &[(PathBuf, PathBuf)],
+    ) -> Self {
+        let files = files
+            .iter()
+            .map(|(target, _)| {
+                target
+                    .strip_prefix(config.output())
+                    .unwrap_or(target)
+                    .to_path_buf()
+            })
+            .collect();
+        Self { title, date, files }
+    }
+}
+
+/// Write the index, replacing any previous one
+pub fn write_index(path: &Path, index: &[PostIndex]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    serde_json::to_writer_pretty(File::create(path)?, index)?;
+    Ok(())
+}
@@ -1,25 +1,81 @@
+pub mod dedup;
+pub mod extract;
 pub mod file;
+pub mod ignore;
+pub mod index;
+pub mod stats;
+pub mod thumbnail;
+pub mod transform;
 
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::FileType,
+    io,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+};
 
-use crate::config::{Config, TransformMethod};
-use chrono::{DateTime, Utc};
+use crate::config::{Config, FileSort, InputFormat, TransformMethod};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc};
 use console::style;
-use file::FanboxDLFileMeta;
-use indicatif::ProgressBar;
-use log::{debug, info, warn};
+use dedup::{link_duplicate, post_hashes, split_by_name, split_duplicates};
+use extract::{extract_zip, find_zip, zip_date};
+use file::{
+    is_dim_cache, sanitize_filename, sanitize_path, truncate_path, truncate_str, FanboxDLFileMeta,
+    OutputLimits,
+};
+use ignore::Ignore;
+use index::PostIndex;
+use log::{debug, error, info, warn};
 use post_archiver::{
-    importer::{post::UnsyncPost, UnsyncContent, UnsyncFileMeta},
+    importer::{post::UnsyncPost, UnsyncContent, UnsyncFileMeta, UnsyncTag},
     manager::PostArchiverManager,
-    PlatformId,
+    PlatformId, Post,
 };
 use rusqlite::Connection;
-use tokio::fs;
+use stats::{MissingFile, ProgressCallback, SyncEvent, SyncStats};
+use tempfile::TempDir;
+use thumbnail::{create_thumbnail, thumbnail_path};
+use tokio::fs::{self, DirEntry};
+use transform::transform_files;
+
+/// Identity of a post in the archive
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PostKey {
+    pub platform: PlatformId,
+    pub source: String,
+}
+
+impl PostKey {
+    pub fn new(post: &UnsyncPost<PathBuf>) -> Self {
+        Self {
+            platform: post.platform,
+            source: post.source.clone(),
+        }
+    }
+}
 
+/// Posts of a creator, with the temporary directories they are read from
+pub struct Posts {
+    pub posts: Vec<UnsyncPost<PathBuf>>,
+    /// Filtered by title or dropped by the post limit
+    pub skipped: usize,
+    pub extracted: Vec<TempDir>,
+}
+
+/// Resolve the posts of a creator.
+///
+/// Zip archives are extracted into temporary directories,
+/// which must be kept until the posts are synced.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 pub async fn get_posts(
-    path: PathBuf,
+    config: &Config,
+    creator: &str,
+    paths: Vec<PathBuf>,
     platform: PlatformId,
-) -> Result<Vec<UnsyncPost<PathBuf>>, Box<dyn std::error::Error>> {
+) -> Result<Posts, Box<dyn std::error::Error>> {
     fn to_contents(files: Vec<UnsyncFileMeta<PathBuf>>) -> Vec<UnsyncContent<PathBuf>> {
         files
             .iter()
@@ -27,40 +83,198 @@ pub async fn get_posts(
             .collect()
     }
 
-    let groups = read_fanbox_dl_archive(path.clone()).await?;
+    let mut posts = vec![];
+    let mut skipped = 0;
+    let mut extracted = vec![];
+    let mut sources = HashSet::new();
+    for path in paths {
+        let mut root = path.clone();
+        let zip = match config.no_zip_extract() {
+            true => None,
+            false => find_zip(&path).await?,
+        };
+        let mut archive_date = None;
+        if let Some(zip) = zip {
+            if config.extract_archive_date_from_zip_metadata() {
+                archive_date = zip_date(&zip).await;
+            }
+            info!("extracting {}", zip.display());
+            let dir = extract_zip(config, zip).await?;
+            root = dir.path().to_path_buf();
+            extracted.push(dir);
+        }
 
-    Ok(groups
-        .into_iter()
-        .map(|group| match group {
-            FanboxDLPost::Ungroup(files) => UnsyncPost::new(
-                platform,
-                path.to_string_lossy().to_string(),
-                "Fanbox archive".to_string(),
-                to_contents(files),
-            ),
-            FanboxDLPost::GroupByPlan(plan, files) => UnsyncPost::new(
-                platform,
-                format!("{} - {}yen", path.to_string_lossy(), plan),
-                "{}yen fanbox archive".to_string(),
-                to_contents(files),
-            ),
-            FanboxDLPost::GroupByPost(date, name, files) => UnsyncPost::new(
-                platform,
-                format!("{} - {}", path.to_string_lossy(), name),
-                name,
-                to_contents(files),
-            )
-            .published(date)
-            .updated(date),
-        })
-        .filter(|post| !post.content.is_empty())
-        .collect())
+        let source = config.post_source(&path);
+        let ignore = Ignore::load(config, &path, &root)?;
+        let groups = read_fanbox_dl_archive(config, root, &ignore).await?;
+        posts.extend(groups.into_iter().filter_map(|group| {
+            let is_post = matches!(group, FanboxDLPost::GroupByPost(..));
+            // filtered by the folder name, whatever the title template
+            let name = match &group {
+                FanboxDLPost::Ungroup(_) => "Fanbox archive".to_string(),
+                FanboxDLPost::GroupByPlan(plan, _) => format!("{}yen", plan),
+                FanboxDLPost::GroupByPost(_, name, _) => name.clone(),
+                FanboxDLPost::GroupByDate(year, month, _) => format!("{:04}-{:02}", year, month),
+            };
+            let plan = match group {
+                FanboxDLPost::GroupByPlan(plan, _) => Some(plan),
+                _ => None,
+            };
+            let mut post = match group {
+                FanboxDLPost::Ungroup(files) => UnsyncPost::new(
+                    platform,
+                    source.clone(),
+                    "Fanbox archive".to_string(),
+                    to_contents(files),
+                ),
+                FanboxDLPost::GroupByPlan(plan, files) => UnsyncPost::new(
+                    platform,
+                    format!("{} - {}yen", source, plan),
+                    post_title(config, creator, Some(plan), None, &format!("{}yen", plan)),
+                    to_contents(files),
+                ),
+                FanboxDLPost::GroupByPost(date, name, files) => UnsyncPost::new(
+                    platform,
+                    format!("{} - {}", source, name),
+                    post_title(config, creator, None, Some(date), &name),
+                    to_contents(files),
+                )
+                .published(date)
+                .updated(date),
+                FanboxDLPost::GroupByDate(year, month, files) => {
+                    let date = NaiveDate::from_ymd_opt(year as i32, month, 1)
+                        .unwrap_or_default()
+                        .and_time(NaiveTime::MIN)
+                        .and_utc();
+                    UnsyncPost::new(
+                        platform,
+                        format!("{} - {:04}-{:02}", source, year, month),
+                        format!("{:04}-{:02} archive", year, month),
+                        to_contents(files),
+                    )
+                    .published(date)
+                    .updated(date)
+                }
+            };
+
+            if post.content.is_empty() {
+                return None;
+            }
+
+            if let Some(plan) = plan.filter(|_| config.tag_by_plan()) {
+                post.tags.push(UnsyncTag {
+                    name: format!("{}yen", plan),
+                    platform: None,
+                });
+            }
+            if config.tag_by_creator() {
+                post.tags.push(UnsyncTag {
+                    name: creator.to_string(),
+                    platform: None,
+                });
+            }
+
+            if let Some(date) = archive_date.filter(|_| post.published.is_none()) {
+                post = post.published(date).updated(date);
+            }
+
+            let filtered = is_post || config.filter_all_post_types();
+            if filtered && !config.filter_post_title(&name) {
+                config.skip(&post.title, "title filtered");
+                skipped += 1;
+                return None;
+            }
+
+            if let Some(template) = config.post_id_template() {
+                post.source = post_id(template, creator, &source, plan, &post);
+            }
+            if !sources.insert(post.source.clone()) {
+                warn!(" post id collision: {}", post.source);
+            }
+            Some(post)
+        }));
+    }
+
+    if let Some(max) = config.max_posts_per_creator() {
+        posts.sort_by_key(|post| post.published);
+        if config.most_recent_first() {
+            posts.reverse();
+        }
+
+        if posts.len() > max {
+            info!("{} posts dropped by limit", posts.len() - max);
+            for post in posts.drain(max..) {
+                config.skip(&post.title, "post limit");
+                skipped += 1;
+            }
+        }
+    }
+
+    Ok(Posts {
+        posts,
+        skipped,
+        extracted,
+    })
 }
 
+/// Title of plan and dated posts, the missing tokens are empty
+fn post_title(
+    config: &Config,
+    creator: &str,
+    plan: Option<u32>,
+    date: Option<DateTime<Utc>>,
+    name: &str,
+) -> String {
+    let template = match (config.post_title_template(), plan) {
+        (Some(template), _) => template,
+        (None, Some(_)) => "{plan}yen fanbox archive",
+        (None, None) => "{name}",
+    };
+
+    let plan = plan.map(|plan| plan.to_string()).unwrap_or_default();
+    let date = date
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    template
+        .replace("{creator}", creator)
+        .replace("{plan}", &plan)
+        .replace("{date}", &date)
+        .replace("{name}", name)
+        .trim()
+        .to_string()
+}
+
+/// Source of a post by the template, the missing tokens are empty
+fn post_id(
+    template: &str,
+    creator: &str,
+    source: &str,
+    plan: Option<u32>,
+    post: &UnsyncPost<PathBuf>,
+) -> String {
+    let plan = plan.map(|plan| plan.to_string()).unwrap_or_default();
+    let date = post
+        .published
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    template
+        .replace("{creator}", creator)
+        .replace("{source}", source)
+        .replace("{date}", &date)
+        .replace("{title}", &post.title)
+        .replace("{plan}", &plan)
+}
+
+const MAX_DEPTH: usize = 5;
+
+/// Metadata sidecar of a downloaded post
+const POST_JSON: &str = "post.json";
+
 pub async fn read_fanbox_dl_archive(
+    config: &Config,
     path: PathBuf,
+    ignore: &Ignore,
 ) -> Result<Vec<FanboxDLPost>, Box<dyn std::error::Error>> {
-    const MAX_DEPTH: usize = 5;
     let mut posts = vec![];
     let mut ungroup = vec![];
 
@@ -72,120 +286,804 @@ pub async fn read_fanbox_dl_archive(
             continue;
         }
 
-        let filetype = entry.file_type().await?;
-        if filetype.is_dir() {
-            let yen = filename.trim_end_matches("yen");
-            let is_plan = yen != filename;
-            if is_plan {
-                let yen = yen.parse::<u32>()?;
-                let files = read_dir_files(entry.path(), 1).await?;
-                posts.push(FanboxDLPost::GroupByPlan(yen, files));
-                continue;
-            }
+        let Some(filetype) = entry_type(config, &entry).await? else {
+            continue;
+        };
+        if ignore.is_ignored(&entry.path(), filetype.is_dir()) {
+            config.skip(entry.path().display(), "ignore file");
+            continue;
+        }
 
-            let (date, name) = filename.split_at(11);
-            let date = DateTime::parse_from_str(date, "%Y-%m-%d-").ok();
-            if let Some(date) = date {
-                let date = date.to_utc();
-                let files = read_dir_files(entry.path(), 1).await?;
-                posts.push(FanboxDLPost::GroupByPost(date, name.to_string(), files));
-                continue;
+        if filetype.is_dir() {
+            let mut post = match config.input_format() {
+                // only the current layout is detected for now
+                InputFormat::Auto | InputFormat::FanboxDlV2 => {
+                    FanboxDLPost::try_from(entry.path().as_path())?
+                }
+                InputFormat::FanboxDlV1 => {
+                    let modified = entry.metadata().await?.modified()?;
+                    FanboxDLPost::from_v1(&entry.path(), modified.into())
+                }
+            };
+            match &mut post {
+                FanboxDLPost::Ungroup(_) if config.infer_post_date_from_files() => {
+                    let files = read_dir_files(config, entry.path(), ignore, 1).await?;
+                    let Some(date) = earliest_modified(&files) else {
+                        debug!(" no date to infer, ignoring: {}", entry.path().display());
+                        continue;
+                    };
+                    debug!(" inferred {} for {}", date.format("%Y-%m-%d"), filename);
+                    let post = FanboxDLPost::GroupByPost(date, config.normalize(&filename), files);
+                    debug!(" {}", post);
+                    posts.push(post);
+                    continue;
+                }
+                FanboxDLPost::Ungroup(_) => {
+                    debug!(" ignoring: {}", entry.path().display());
+                    continue;
+                }
+                FanboxDLPost::GroupByPost(_, name, _) => {
+                    *name = config.normalize(name);
+                    let sidecar = entry.path().join(POST_JSON);
+                    if config.warn_on_missing_post_json() && !fs::try_exists(&sidecar).await? {
+                        warn!(" missing {}: {}", POST_JSON, entry.path().display());
+                    }
+                }
+                FanboxDLPost::GroupByPlan(..) | FanboxDLPost::GroupByDate(..) => {}
             }
 
-            debug!(" ignoring: {}", entry.path().display());
+            post.populate_files(config, entry.path(), ignore).await?;
+            debug!(" {}", post);
+            posts.push(post);
         } else if filetype.is_file() {
-            ungroup.push(UnsyncFileMeta::from_path(entry.path()));
+            ungroup.push(entry.path());
         } else {
             warn!(" {} is not a file or directory", entry.path().display());
         }
     }
 
+    let mut ungroup = read_files(config, ungroup).await?;
+    sort_files(config, &mut ungroup);
     posts.push(FanboxDLPost::Ungroup(ungroup));
 
-    #[async_recursion::async_recursion]
-    async fn read_dir_files(
-        path: PathBuf,
-        level: usize,
-    ) -> Result<Vec<UnsyncFileMeta<PathBuf>>, Box<dyn std::error::Error>> {
-        let mut list = vec![];
+    Ok(posts)
+}
+
+#[async_recursion::async_recursion]
+async fn read_dir_files(
+    config: &Config,
+    path: PathBuf,
+    ignore: &Ignore,
+    level: usize,
+) -> Result<Vec<UnsyncFileMeta<PathBuf>>, Box<dyn std::error::Error>> {
+    if level > MAX_DEPTH {
+        warn!(" over expect depth {}", MAX_DEPTH);
+        return Ok(vec![]);
+    }
+
+    let mut files = vec![];
+    let mut dirs = vec![];
 
-        if level > MAX_DEPTH {
-            warn!(" over expect depth {}", MAX_DEPTH);
-            return Ok(list);
+    let mut entrys = fs::read_dir(path).await?;
+    while let Ok(Some(entry)) = entrys.next_entry().await {
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if filename.starts_with('.') {
+            debug!(" ignoring: {}", entry.path().display());
+            continue;
         }
 
-        let mut dirs = vec![];
+        let Some(filetype) = entry_type(config, &entry).await? else {
+            continue;
+        };
+        if ignore.is_ignored(&entry.path(), filetype.is_dir()) {
+            config.skip(entry.path().display(), "ignore file");
+            continue;
+        }
 
-        let mut entrys = fs::read_dir(path).await?;
-        while let Ok(Some(entry)) = entrys.next_entry().await {
-            let filename = entry.file_name().to_string_lossy().to_string();
-            if filename.starts_with('.') {
-                debug!(" ignoring: {}", entry.path().display());
-                continue;
-            }
+        if filetype.is_dir() {
+            let files = read_dir_files(config, entry.path(), ignore, level + 1);
+            dirs.push((filename, files));
+        } else if filetype.is_file() {
+            files.push(entry.path());
+        } else {
+            warn!(" {} is not a file or directory", entry.path().display());
+        }
+    }
 
-            let filetype = entry.file_type().await?;
-            if filetype.is_dir() {
-                dirs.push(read_dir_files(entry.path(), level + 1));
-            } else if filetype.is_file() {
-                list.push(UnsyncFileMeta::from_path(entry.path()));
-            } else {
-                warn!(" {} is not a file or directory", entry.path().display());
+    let mut list = read_files(config, files).await?;
+    for (name, files) in dirs {
+        let mut files = files.await?;
+        if level < config.output_tree_depth() {
+            for file in files.iter_mut() {
+                file.filename = format!("{}/{}", name, file.filename);
             }
         }
+        list.extend(files);
+    }
+
+    if level == 1 {
+        sort_files(config, &mut list);
+    }
+    Ok(list)
+}
+
+/// The earliest modified time of the files, `None` if several files share one
+fn earliest_modified(files: &[UnsyncFileMeta<PathBuf>]) -> Option<DateTime<Utc>> {
+    let times = files
+        .iter()
+        .map(|file| std::fs::metadata(&file.data).and_then(|metadata| metadata.modified()))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+
+    let earliest = *times.iter().min()?;
+    // copied or extracted files share a made up time
+    if times.len() > 1 && times.iter().all(|time| *time == earliest) {
+        return None;
+    }
+    Some(earliest.into())
+}
+
+/// Type of the entry, or of the symlink target with `--follow-symlinks`.
+///
+/// `None` for the skipped symlinks.
+pub async fn entry_type(config: &Config, entry: &DirEntry) -> io::Result<Option<FileType>> {
+    let filetype = entry.file_type().await?;
+    if !filetype.is_symlink() {
+        return Ok(Some(filetype));
+    }
 
-        for dir in dirs {
-            let files = dir.await?;
-            list.extend(files);
+    if !config.follow_symlinks() {
+        warn!(" skipping symlink: {}", entry.path().display());
+        return Ok(None);
+    }
+    match fs::metadata(entry.path()).await {
+        Ok(metadata) => Ok(Some(metadata.file_type())),
+        Err(e) => {
+            warn!(" broken symlink {}: {}", entry.path().display(), e);
+            Ok(None)
         }
+    }
+}
 
-        Ok(list)
+fn sort_files(config: &Config, files: &mut [UnsyncFileMeta<PathBuf>]) {
+    let modified = |file: &UnsyncFileMeta<PathBuf>| {
+        std::fs::metadata(&file.data)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let size = |file: &UnsyncFileMeta<PathBuf>| {
+        std::fs::metadata(&file.data)
+            .map(|metadata| metadata.len())
+            .ok()
+    };
+
+    match config.file_sort() {
+        FileSort::None => {}
+        FileSort::Name => files.sort_by(|a, b| a.data.cmp(&b.data)),
+        FileSort::NameReverse => files.sort_by(|a, b| b.data.cmp(&a.data)),
+        FileSort::Mtime => files.sort_by_cached_key(modified),
+        FileSort::MtimeReverse => {
+            files.sort_by_cached_key(modified);
+            files.reverse();
+        }
+        FileSort::Size => files.sort_by_cached_key(size),
+        FileSort::SizeReverse => {
+            files.sort_by_cached_key(size);
+            files.reverse();
+        }
     }
+}
 
-    Ok(posts)
+/// Read the file metas concurrently, keeping the order of the paths
+async fn read_files(
+    config: &Config,
+    paths: Vec<PathBuf>,
+) -> Result<Vec<UnsyncFileMeta<PathBuf>>, Box<dyn std::error::Error>> {
+    let config = Arc::new(config.clone());
+    let tasks: Vec<_> = paths
+        .into_iter()
+        .map(|path| {
+            let config = config.clone();
+            tokio::spawn(async move { read_file(&config, path).await.map_err(|e| e.to_string()) })
+        })
+        .collect();
+
+    let mut list = vec![];
+    for task in tasks {
+        list.extend(task.await??);
+    }
+    Ok(list)
 }
 
+/// Read the file meta, or `None` if the file is filtered out
+async fn read_file(
+    config: &Config,
+    path: PathBuf,
+) -> Result<Option<UnsyncFileMeta<PathBuf>>, Box<dyn std::error::Error>> {
+    if is_dim_cache(&path) {
+        config.skip(path.display(), "image size cache");
+        return Ok(None);
+    }
+
+    if config.is_part_file(&path) {
+        config.skip(path.display(), "partial download");
+        return Ok(None);
+    }
+
+    if config.skip_files_without_extension() && path.extension().is_none() {
+        config.skip(path.display(), "no extension");
+        return Ok(None);
+    }
+
+    if !config.filter_extension(&path) {
+        config.skip(path.display(), "extension filtered");
+        return Ok(None);
+    }
+
+    // reading the image size blocks
+    let permit = config.reads().acquire_owned().await?;
+    let provider = config.metadata_provider();
+    let file =
+        tokio::task::spawn_blocking(move || UnsyncFileMeta::from_path(path, &*provider)).await?;
+    drop(permit);
+
+    let size = |key: &str| file.extra.get(key).and_then(|v| v.as_u64());
+    let dimensions = size("width").zip(size("height"));
+    if !config.filter_image_size(dimensions) {
+        config.skip(file.data.display(), "image too small");
+        return Ok(None);
+    }
+
+    Ok(Some(file))
+}
+
+#[derive(Debug, Clone)]
 pub enum FanboxDLPost {
     Ungroup(Vec<UnsyncFileMeta<PathBuf>>),
     GroupByPlan(u32, Vec<UnsyncFileMeta<PathBuf>>),
     GroupByPost(DateTime<Utc>, String, Vec<UnsyncFileMeta<PathBuf>>),
+    /// Posts grouped by `YYYY-MM`
+    GroupByDate(u32, u32, Vec<UnsyncFileMeta<PathBuf>>),
+}
+
+impl FanboxDLPost {
+    /// Classify a directory of the older layout, where every directory is a post.
+    /// The date is taken from the name if it has one, otherwise the given time is used.
+    pub fn from_v1(path: &Path, modified: DateTime<Utc>) -> Self {
+        match FanboxDLPost::try_from(path) {
+            Ok(post @ FanboxDLPost::GroupByPost(..)) => post,
+            _ => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                FanboxDLPost::GroupByPost(modified, name, vec![])
+            }
+        }
+    }
+
+    pub fn files(&self) -> &[UnsyncFileMeta<PathBuf>] {
+        match self {
+            FanboxDLPost::Ungroup(list)
+            | FanboxDLPost::GroupByPlan(_, list)
+            | FanboxDLPost::GroupByPost(_, _, list)
+            | FanboxDLPost::GroupByDate(_, _, list) => list,
+        }
+    }
+
+    /// Read the files of the post directory
+    pub async fn populate_files(
+        &mut self,
+        config: &Config,
+        path: PathBuf,
+        ignore: &Ignore,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let files = read_dir_files(config, path, ignore, 1).await?;
+        match self {
+            FanboxDLPost::Ungroup(list)
+            | FanboxDLPost::GroupByPlan(_, list)
+            | FanboxDLPost::GroupByPost(_, _, list)
+            | FanboxDLPost::GroupByDate(_, _, list) => *list = files,
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FanboxDLPost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FanboxDLPost::Ungroup(files) => write!(f, "[ungroup] {} files", files.len()),
+            FanboxDLPost::GroupByPlan(plan, files) => {
+                write!(f, "[plan {}yen] {} files", plan, files.len())
+            }
+            FanboxDLPost::GroupByPost(date, name, files) => write!(
+                f,
+                "[post {} - {}] {} files",
+                date.format("%Y-%m-%d"),
+                name,
+                files.len()
+            ),
+            FanboxDLPost::GroupByDate(year, month, files) => {
+                write!(f, "[month {:04}-{:02}] {} files", year, month, files.len())
+            }
+        }
+    }
+}
+
+/// Classify a directory by its name, the files are left empty
+impl TryFrom<&Path> for FanboxDLPost {
+    type Error = ParseIntError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if let Some(yen) = filename.strip_suffix("yen") {
+            return Ok(FanboxDLPost::GroupByPlan(yen.parse()?, vec![]));
+        }
+
+        // e.g. `2024-01`
+        let month = NaiveDate::parse_from_str(&format!("{}-01", filename), "%Y-%m-%d");
+        if let (7, Ok(month)) = (filename.len(), month) {
+            return Ok(FanboxDLPost::GroupByDate(
+                month.year() as u32,
+                month.month(),
+                vec![],
+            ));
+        }
+
+        // e.g. `2024-01-31-title`
+        let date = filename
+            .get(..11)
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d-").ok());
+        if let Some(date) = date {
+            let date = date.and_time(NaiveTime::MIN).and_utc();
+            let name = filename[11..].to_string();
+            return Ok(FanboxDLPost::GroupByPost(date, name, vec![]));
+        }
+
+        Ok(FanboxDLPost::Ungroup(vec![]))
+    }
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(posts = posts.len())))]
 pub async fn sync_posts(
-    manager: &mut PostArchiverManager<Connection>,
+    mut manager: Option<&mut PostArchiverManager<Connection>>,
     config: &Config,
     posts: Vec<UnsyncPost<PathBuf>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let multi = config.multi();
-    let total = multi.add(ProgressBar::new(posts.len() as u64));
+    stats: &mut SyncStats,
+    callback: Option<ProgressCallback>,
+) -> Result<Vec<PostIndex>, Box<dyn std::error::Error>> {
+    // several creators may resolve to the same posts,
+    // without database the posts have no identity in the archive
+    let posts: Vec<_> = {
+        let mut processed = config.processed().lock().unwrap();
+        posts
+            .into_iter()
+            .filter(|post| {
+                if config.no_database() {
+                    return true;
+                }
+                let new = processed.insert(PostKey::new(post));
+                if !new {
+                    warn!(" post already synced in this run: {}", post.source);
+                    stats.posts_skipped += 1;
+                }
+                new
+            })
+            .collect()
+    };
+
+    let fallback;
+    let config = match config.transform() == TransformMethod::Move && !is_source_writable(&posts) {
+        true if config.auto_fallback_copy() => {
+            warn!("Source filesystem appears read-only; falling back to Copy.");
+            fallback = config.with_transform(TransformMethod::Copy);
+            &fallback
+        }
+        true => {
+            warn!("Source filesystem appears read-only; Move may fail. Consider using Copy.");
+            config
+        }
+        false => config,
+    };
 
-    for post in posts {
-        sync_post(manager, config, post)?;
-        total.inc(1);
+    (config.progress().posts_total).fetch_add(posts.len() as u64, Ordering::Relaxed);
+    let total = config.progress_bar(posts.len(), callback.is_some());
+    total.set_style(config.progress_style());
+    total.set_prefix("posts");
+
+    let mut index = vec![];
+    let mut posts = posts.into_iter();
+    loop {
+        let batch: Vec<_> = posts.by_ref().take(config.batch_size()).collect();
+        if batch.is_empty() {
+            break;
+        }
+
+        let count = batch.len() as u64;
+        let synced = sync_batch(
+            manager.as_deref_mut(),
+            config,
+            batch,
+            stats,
+            callback.as_ref(),
+        );
+        index.extend(synced.await?);
+        total.inc(count);
+        config.progress().posts.fetch_add(count, Ordering::Relaxed);
     }
     total.finish_and_clear();
 
     let success = total.position();
     let total = total.length().unwrap();
+    stats.posts_synced += success as usize;
 
     info!("");
     info!("{} {}", total, style("total").dim());
     info!("{} {}", success, style("success").green());
     info!("{} {}", total - success, style("failed").red());
+    if config.dedup().is_some() || config.names().is_some() {
+        info!(
+            "{} {}",
+            stats.files_deduplicated,
+            style("deduplicated files").cyan()
+        );
+    }
+    if stats.files_skipped > 0 {
+        info!("{} {}", stats.files_skipped, style("skipped files").dim());
+    }
     info!("");
-    Ok(())
+
+    if let Some(callback) = callback {
+        callback(SyncEvent::AllDone {
+            stats: stats.clone(),
+        });
+    }
+    Ok(index)
+}
+
+/// Try to create a file beside the first source file
+fn is_source_writable(posts: &[UnsyncPost<PathBuf>]) -> bool {
+    let source =
+        posts
+            .iter()
+            .flat_map(|post| post.content.iter())
+            .find_map(|content| match content {
+                UnsyncContent::File(file) => Some(&file.data),
+                UnsyncContent::Text(_) => None,
+            });
+
+    match source.and_then(|source| source.parent()) {
+        Some(dir) => tempfile::tempfile_in(dir).is_ok(),
+        None => true,
+    }
+}
+
+/// Sync a batch of posts in one transaction, or only transform the files without database
+async fn sync_batch(
+    manager: Option<&mut PostArchiverManager<Connection>>,
+    config: &Config,
+    posts: Vec<UnsyncPost<PathBuf>>,
+    stats: &mut SyncStats,
+    callback: Option<&ProgressCallback>,
+) -> Result<Vec<PostIndex>, Box<dyn std::error::Error>> {
+    let tags: Vec<UnsyncTag> = config
+        .tags()
+        .iter()
+        .map(|name| UnsyncTag {
+            name: name.clone(),
+            platform: None,
+        })
+        .collect();
+
+    let limits = config
+        .output_encoding_check()
+        .then(|| OutputLimits::detect(config.output()));
+
+    let manager = manager.map(|manager| manager.transaction()).transpose()?;
+    let mut index = vec![];
+    let mut files = vec![];
+    let mut thumbnails = vec![];
+    let mut links = vec![];
+    let mut titles = HashMap::new();
+    let mut counts = vec![];
+    for mut post in posts {
+        post.tags.extend(tags.iter().cloned());
+        let post = prepare_post(config, post, limits.as_ref());
+        let (title, date) = (post.title.clone(), post.published);
+        if let Some(callback) = callback {
+            callback(SyncEvent::PostStart {
+                title: title.clone(),
+            });
+        }
+        let image = first_image(&post);
+        let hashes = post_hashes(&post, config.hash_algorithm());
+        let order = file_order(&post);
+        let (dir, mut post_files) = match &manager {
+            Some(manager) => {
+                let (id, files) = post.sync(manager)?;
+                (config.output().join(Post::directory(id)), files)
+            }
+            None => (
+                config.output().join(post_dir_name(config, &post)),
+                post_files(config, post),
+            ),
+        };
+        // synced files are unordered
+        post_files.sort_by_key(|(target, _)| order.iter().position(|name| target.ends_with(name)));
+        index.push(PostIndex::new(config, title.clone(), date, &post_files));
+        for (_, source) in post_files.iter() {
+            titles.insert(source.clone(), title.clone());
+        }
+        counts.push((title.clone(), post_files.len()));
+        let image = image.and_then(|image| {
+            post_files
+                .iter()
+                .find(|(target, _)| target.ends_with(&image))
+                .map(|(target, _)| target.clone())
+        });
+        if let Some(image) = image.filter(|_| config.create_thumbnails().is_some()) {
+            // never replace a file of the post
+            let thumbnail = thumbnail_path(&image);
+            match post_files.iter().any(|(target, _)| *target == thumbnail) {
+                true => warn!(" post has its own {}", thumbnail.display()),
+                false => thumbnails.push(image),
+            }
+        }
+        if let Some(dedup) = config.dedup() {
+            let (unique, duplicates) = split_duplicates(dedup, &hashes, &dir, post_files);
+            links.extend(duplicates.into_iter().map(|link| (link, title.clone())));
+            post_files = unique;
+        }
+        if let Some(names) = config.names() {
+            let size_match = config.name_match_requires_size_match();
+            let (unique, duplicates) = split_by_name(names, post_files, size_match);
+            links.extend(duplicates.into_iter().map(|link| (link, title.clone())));
+            post_files = unique;
+        }
+        files.extend(post_files);
+    }
+    let failed = transform_files(config, files, stats, callback).await?;
+    let mut failed_posts: HashMap<String, usize> = HashMap::new();
+    for (source, e) in failed {
+        let title = titles.remove(&source).unwrap_or_default();
+        *failed_posts.entry(title.clone()).or_default() += 1;
+        if e.kind() != io::ErrorKind::NotFound {
+            continue;
+        }
+        stats.files_missing.push(MissingFile {
+            creator: stats.creator.clone(),
+            post: title,
+            filename: source
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        });
+    }
+
+    // the existing files may be transferred in this batch
+    for ((target, existing), title) in links {
+        match link_duplicate(&target, &existing).await {
+            Ok(()) => stats.files_deduplicated += 1,
+            Err(e) if config.fail_fast() => {
+                stats.files_failed += 1;
+                return Err(format!("{}: {}", target.display(), e).into());
+            }
+            Err(e) => {
+                error!(" {}: {}", target.display(), e);
+                stats.files_failed += 1;
+                *failed_posts.entry(title).or_default() += 1;
+            }
+        }
+    }
+
+    if let Some(size) = config.create_thumbnails() {
+        for image in thumbnails {
+            create_thumbnail(image, size, config.thumbnail_overwrite()).await;
+        }
+    }
+    if let Some(manager) = manager {
+        manager.commit()?;
+    }
+
+    if let Some(callback) = callback {
+        for (title, files) in counts {
+            let files_failed = failed_posts.get(&title).copied().unwrap_or(0);
+            callback(SyncEvent::PostComplete {
+                title,
+                files_ok: files - files_failed,
+                files_failed,
+            });
+        }
+    }
+    Ok(index)
+}
+
+/// Filenames of the post files in content order
+fn file_order(post: &UnsyncPost<PathBuf>) -> Vec<String> {
+    post.content
+        .iter()
+        .filter_map(|content| match content {
+            UnsyncContent::File(file) => Some(file.filename.clone()),
+            UnsyncContent::Text(_) => None,
+        })
+        .collect()
+}
+
+/// Filename of the first image of the post
+fn first_image(post: &UnsyncPost<PathBuf>) -> Option<String> {
+    post.content.iter().find_map(|content| match content {
+        UnsyncContent::File(file) if file.mime.starts_with("image/") => Some(file.filename.clone()),
+        _ => None,
+    })
+}
+
+/// Target paths of the post files without database, `<output>/<date>-<title>/<filename>`
+fn post_files(config: &Config, post: UnsyncPost<PathBuf>) -> Vec<(PathBuf, PathBuf)> {
+    let dir = config.output().join(post_dir_name(config, &post));
+    post.content
+        .into_iter()
+        .filter_map(|content| match content {
+            UnsyncContent::File(file) => Some((dir.join(file.filename), file.data)),
+            UnsyncContent::Text(_) => None,
+        })
+        .collect()
+}
+
+/// Folder of the post files without database
+fn post_dir_name(config: &Config, post: &UnsyncPost<PathBuf>) -> String {
+    let title = sanitize_filename(&post.title, config.sanitize_replacement());
+    match post.published {
+        Some(date) => format!("{}-{}", date.format(config.output_date_format()), title),
+        None => title,
+    }
 }
 
-fn sync_post(
-    manager: &mut PostArchiverManager<Connection>,
+/// Longest post folder in the archive, `<chunk>/<index>`
+const POST_DIR_MAX: usize = 24;
+
+/// Adjust the title and target filenames of the post
+fn prepare_post(
     config: &Config,
-    post: UnsyncPost<PathBuf>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let manager = manager.transaction()?;
-    match config.transform() {
-        TransformMethod::Copy => manager.import_post_with_files(post)?,
-        TransformMethod::Move => manager.import_post_with_rename_files(post)?,
+    mut post: UnsyncPost<PathBuf>,
+    limits: Option<&OutputLimits>,
+) -> UnsyncPost<PathBuf> {
+    if let Some(max) = config.max_title_length() {
+        let title = truncate_str(&post.title, max);
+        if title != post.title {
+            debug!(" truncated: {} -> {}", post.title, title);
+            post.title = title.to_string();
+        }
+    }
+
+    let output = config.output().as_os_str().len();
+    let parent = match (limits, config.no_database()) {
+        (Some(limits), true) => {
+            let dir = post_dir_name(config, &post);
+            let over = dir.len().saturating_sub(limits.name_limit(output));
+            if over > 0 {
+                let title = truncate_str(&post.title, post.title.len().saturating_sub(over));
+                warn!(" folder name too long: {} -> {}", post.title, title);
+                post.title = title.to_string();
+            }
+            output + 1 + post_dir_name(config, &post).len()
+        }
+        _ => output + 1 + POST_DIR_MAX,
     };
-    manager.commit()?;
-    Ok(())
+
+    for content in post.content.iter_mut() {
+        let UnsyncContent::File(file) = content else {
+            continue;
+        };
+
+        if config.sanitize_filenames() {
+            file.filename = sanitize_path(&file.filename, config.sanitize_replacement());
+        }
+
+        if let Some(max) = config.max_title_length() {
+            let filename = truncate_path(&file.filename, max);
+            if filename != file.filename {
+                debug!(" truncated: {} -> {}", file.filename, filename);
+                file.filename = filename;
+            }
+        }
+
+        if let Some(limits) = limits {
+            if !limits.is_valid(&file.filename) {
+                let filename = sanitize_path(&file.filename, config.sanitize_replacement());
+                warn!(" invalid filename: {} -> {}", file.filename, filename);
+                file.filename = filename;
+            }
+
+            // the folders kept by `--output-tree-depth` are part of the parent
+            let folders = file.filename.rfind('/').map_or(0, |i| i + 1);
+            let max = limits.name_limit(parent + folders);
+            if file.filename.len() - folders > max {
+                let filename = truncate_path(&file.filename, max);
+                warn!(" filename too long: {} -> {}", file.filename, filename);
+                file.filename = filename;
+            }
+        }
+
+        if config.compress_output().is_some() {
+            file.filename.push_str(".zst");
+            let mime = std::mem::replace(&mut file.mime, "application/zstd".to_string());
+            file.extra.insert("original_mime".to_string(), mime.into());
+        }
+    }
+
+    post
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn classify(name: &str) -> FanboxDLPost {
+        FanboxDLPost::try_from(Path::new("creator").join(name).as_path()).unwrap()
+    }
+
+    #[test]
+    fn classify_plan() {
+        assert!(matches!(
+            classify("500yen"),
+            FanboxDLPost::GroupByPlan(500, _)
+        ));
+        assert!(FanboxDLPost::try_from(Path::new("freeyen")).is_err());
+    }
+
+    #[test]
+    fn classify_month() {
+        assert!(matches!(
+            classify("2024-01"),
+            FanboxDLPost::GroupByDate(2024, 1, _)
+        ));
+        assert!(matches!(classify("2024-13"), FanboxDLPost::Ungroup(_)));
+    }
+
+    #[test]
+    fn classify_dated() {
+        let FanboxDLPost::GroupByPost(date, name, _) = classify("2024-01-31-title") else {
+            panic!("not a dated post");
+        };
+        assert_eq!(date, Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap());
+        assert_eq!(name, "title");
+
+        let FanboxDLPost::GroupByPost(_, name, _) = classify("2024-01-31-") else {
+            panic!("not a dated post");
+        };
+        assert_eq!(name, "");
+    }
+
+    #[test]
+    fn classify_short_names() {
+        assert!(matches!(classify("a"), FanboxDLPost::Ungroup(_)));
+        assert!(matches!(classify("2024-01-31"), FanboxDLPost::Ungroup(_)));
+        // byte 11 is not a char boundary
+        assert!(matches!(classify("イラスト"), FanboxDLPost::Ungroup(_)));
+    }
+
+    #[test]
+    fn post_id_template() {
+        let date = Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap();
+        let post = UnsyncPost::new(PlatformId::new(0), String::new(), "title".into(), vec![])
+            .published(date);
+
+        let id = post_id(
+            "{creator}/{date}/{title}/{plan}",
+            "alice",
+            "src",
+            None,
+            &post,
+        );
+        assert_eq!(id, "alice/2024-01-31/title/");
+        let id = post_id("{source} - {plan}yen", "alice", "src", Some(500), &post);
+        assert_eq!(id, "src - 500yen");
+    }
 }
@@ -0,0 +1,145 @@
+use std::{
+    error::Error,
+    fs::File,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+/// Sync results of a creator
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncStats {
+    #[serde(rename = "creator_id")]
+    pub creator: String,
+    pub posts_total: usize,
+    pub posts_synced: usize,
+    pub posts_skipped: usize,
+    pub files_total: usize,
+    pub files_synced: usize,
+    pub files_failed: usize,
+    /// Already transferred or up to date
+    pub files_skipped: usize,
+    pub bytes_synced: u64,
+    #[serde(skip)]
+    pub files_deduplicated: usize,
+    #[serde(skip)]
+    pub files_missing: Vec<MissingFile>,
+}
+
+/// A file whose source was not found when transferring it
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingFile {
+    pub creator: String,
+    pub post: String,
+    pub filename: String,
+}
+
+impl SyncStats {
+    pub fn new(creator: String) -> Self {
+        Self {
+            creator,
+            ..Default::default()
+        }
+    }
+
+    /// Sum of the statistics of several creators
+    pub fn total(stats: &[SyncStats]) -> Self {
+        let mut total = Self::default();
+        for stats in stats {
+            total.posts_total += stats.posts_total;
+            total.posts_synced += stats.posts_synced;
+            total.posts_skipped += stats.posts_skipped;
+            total.files_total += stats.files_total;
+            total.files_synced += stats.files_synced;
+            total.files_failed += stats.files_failed;
+            total.files_skipped += stats.files_skipped;
+            total.bytes_synced += stats.bytes_synced;
+            total.files_deduplicated += stats.files_deduplicated;
+            total
+                .files_missing
+                .extend(stats.files_missing.iter().cloned());
+        }
+        total
+    }
+}
+
+/// Progress of `sync_posts`, for library consumers replacing the progress bars
+#[derive(Debug, Clone)]
+pub enum SyncEvent {
+    PostStart {
+        title: String,
+    },
+    FileComplete {
+        filename: String,
+        success: bool,
+    },
+    PostComplete {
+        title: String,
+        files_ok: usize,
+        files_failed: usize,
+    },
+    AllDone {
+        stats: SyncStats,
+    },
+}
+
+pub type ProgressCallback = Arc<dyn Fn(SyncEvent) + Send + Sync>;
+
+/// Progress of the whole run
+#[derive(Debug, Default)]
+pub struct ProgressCounters {
+    pub posts_total: AtomicU64,
+    pub posts: AtomicU64,
+    pub files_total: AtomicU64,
+    pub files: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+impl ProgressCounters {
+    pub fn snapshot(&self) -> serde_json::Value {
+        json!({
+            "posts": self.posts.load(Ordering::Relaxed),
+            "files": self.files.load(Ordering::Relaxed),
+            "bytes": self.bytes.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Print a progress snapshot to stderr as a JSON line on every `SIGUSR1`
+#[cfg(unix)]
+pub fn report_progress_on_signal(progress: Arc<ProgressCounters>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            eprintln!("{}", progress.snapshot());
+        }
+    });
+    Ok(())
+}
+
+/// Write one row per creator
+pub fn write_statistics(path: &Path, stats: &[SyncStats]) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for stats in stats {
+        writer.serialize(stats)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the missing files of all creators as a JSON array
+pub fn write_missing_files(path: &Path, stats: &[SyncStats]) -> Result<usize, Box<dyn Error>> {
+    let missing: Vec<_> = stats
+        .iter()
+        .flat_map(|stats| &stats.files_missing)
+        .collect();
+    serde_json::to_writer_pretty(File::create(path)?, &missing)?;
+    Ok(missing.len())
+}
@@ -0,0 +1,45 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat};
+use log::{debug, warn};
+use tokio::sync::oneshot;
+
+pub const THUMBNAIL_FILENAME: &str = "thumbnail.jpg";
+
+/// `thumbnail.jpg` beside the image
+pub fn thumbnail_path(image: &Path) -> PathBuf {
+    image.with_file_name(THUMBNAIL_FILENAME)
+}
+
+/// Write `thumbnail.jpg` beside the image, fitting in `size` x `size`
+pub async fn create_thumbnail(image: PathBuf, size: u32, overwrite: bool) {
+    let target = thumbnail_path(&image);
+    if !overwrite && target.exists() {
+        debug!(" thumbnail exists: {}", target.display());
+        return;
+    }
+
+    // resizing is cpu bound, keep it off the async executor
+    let (sender, receiver) = oneshot::channel();
+    rayon::spawn({
+        let target = target.clone();
+        move || {
+            let _ = sender.send(resize(&image, &target, size).map_err(|e| e.to_string()));
+        }
+    });
+
+    match receiver.await {
+        Ok(Ok(())) => debug!(" thumbnail: {}", target.display()),
+        Ok(Err(e)) => warn!(" failed to create {}: {}", target.display(), e),
+        Err(_) => warn!(" failed to create {}", target.display()),
+    }
+}
+
+fn resize(image: &Path, target: &Path, size: u32) -> Result<(), Box<dyn Error>> {
+    let thumbnail = image::open(image)?.thumbnail(size, size);
+    DynamicImage::ImageRgb8(thumbnail.to_rgb8()).save_with_format(target, ImageFormat::Jpeg)?;
+    Ok(())
+}
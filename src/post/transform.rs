@@ -0,0 +1,276 @@
+use std::{
+    io::{self, Read, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Once},
+};
+
+use governor::DefaultDirectRateLimiter;
+use log::{debug, error, warn};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Handle,
+    sync::{watch, Semaphore},
+    task::JoinSet,
+};
+
+use super::stats::{ProgressCallback, SyncEvent, SyncStats};
+use crate::config::{Config, TransformMethod};
+
+/// Default chunk size of the manual copy loops
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Transfer the files into the archive, at most `limit` files at once.
+///
+/// Returns the sources that failed to transfer.
+pub async fn transform_files(
+    config: &Config,
+    files: Vec<(PathBuf, PathBuf)>,
+    stats: &mut SyncStats,
+    callback: Option<&ProgressCallback>,
+) -> Result<Vec<(PathBuf, io::Error)>, Box<dyn std::error::Error>> {
+    stats.files_total += files.len();
+    (config.progress().files_total).fetch_add(files.len() as u64, Ordering::Relaxed);
+
+    let progress = config.progress_bar(files.len(), callback.is_some());
+    progress.set_style(config.secondary_style());
+    progress.set_prefix("files");
+
+    let semaphore = Arc::new(Semaphore::new(config.limit()));
+    let (failed, watch) = watch::channel(false);
+    let config = Arc::new(config.clone());
+    let mut tasks = JoinSet::new();
+
+    for (target, source) in files {
+        let permit = semaphore.clone().acquire_owned().await?;
+        if *watch.borrow() {
+            break;
+        }
+
+        let config = config.clone();
+        let failed = failed.clone();
+        tasks.spawn(async move {
+            let transfer = transform_file(&config, &source, &target);
+            let result = match config.file_transform_timeout() {
+                Some(timeout) => tokio::time::timeout(timeout, transfer)
+                    .await
+                    .unwrap_or_else(|_| {
+                        let message =
+                            format!("file operation timed out after {}s", timeout.as_secs());
+                        Err(io::Error::new(io::ErrorKind::TimedOut, message))
+                    }),
+                None => transfer.await,
+            };
+            drop(permit);
+            if result.is_err() && config.fail_fast() {
+                failed.send_replace(true);
+            }
+            (target, source, result)
+        });
+    }
+
+    let mut errors = vec![];
+    while let Some(result) = tasks.join_next().await {
+        progress.inc(1);
+        let (target, source, result) = result?;
+        if let Some(callback) = callback {
+            callback(SyncEvent::FileComplete {
+                filename: target
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                success: result.is_ok(),
+            });
+        }
+        match result {
+            Ok(None) => stats.files_skipped += 1,
+            Ok(Some(bytes)) => {
+                stats.files_synced += 1;
+                stats.bytes_synced += bytes;
+                let progress = config.progress();
+                progress.files.fetch_add(1, Ordering::Relaxed);
+                progress.bytes.fetch_add(bytes, Ordering::Relaxed);
+            }
+            Err(e) if config.fail_fast() => {
+                stats.files_failed += 1;
+                tasks.abort_all();
+                progress.finish_and_clear();
+                return Err(format!("{}: {}", source.display(), e).into());
+            }
+            Err(e) => {
+                error!(" {}: {}", source.display(), e);
+                errors.push((source, e));
+            }
+        }
+    }
+    stats.files_failed += errors.len();
+    progress.finish_and_clear();
+
+    if !errors.is_empty() {
+        warn!("{} files failed to transform", errors.len());
+    }
+    Ok(errors)
+}
+
+/// Transfer a file, returns the transferred bytes or `None` if skipped
+async fn transform_file(config: &Config, source: &Path, target: &Path) -> io::Result<Option<u64>> {
+    if config.overwrite_if_newer() {
+        if is_up_to_date(source, target).await? {
+            debug!(" up to date: {}", target.display());
+            return Ok(None);
+        }
+    } else if config.skip_already_transferred() && fs::metadata(target).await.is_ok() {
+        debug!(" already transferred: {}", target.display());
+        return Ok(None);
+    }
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    match (
+        config.transform(),
+        config.bandwidth(),
+        config.compress_output(),
+    ) {
+        (TransformMethod::Copy, bandwidth, Some(level)) => {
+            let (source, target) = (source.to_path_buf(), target.to_path_buf());
+            let buffer = config.read_buffer_size();
+            let bandwidth = bandwidth
+                .cloned()
+                .map(|bandwidth| (bandwidth, Handle::current()));
+            let compress = move || compress_file(&source, &target, level, buffer, bandwidth);
+            tokio::task::spawn_blocking(compress).await?
+        }
+        (TransformMethod::Copy, None, None) => fs::copy(source, target).await.map(|_| ()),
+        (TransformMethod::Copy, Some(bandwidth), None) => {
+            copy_limited(bandwidth, source, target, config.read_buffer_size()).await
+        }
+        (TransformMethod::Move, _, _) => fs::rename(source, target).await,
+        (TransformMethod::Reflink, _, _) => {
+            let (source, target) = (source.to_path_buf(), target.to_path_buf());
+            tokio::task::spawn_blocking(move || reflink_or_copy(&source, &target)).await?
+        }
+    }?;
+
+    Ok(Some(fs::metadata(target).await?.len()))
+}
+
+/// Compress in chunks, waiting for the shared rate limiter before each write
+fn compress_file(
+    source: &Path,
+    target: &Path,
+    level: i32,
+    buffer: usize,
+    bandwidth: Option<(Arc<DefaultDirectRateLimiter>, Handle)>,
+) -> io::Result<()> {
+    let mut reader = std::fs::File::open(source)?;
+    let mut encoder = zstd::Encoder::new(std::fs::File::create(target)?, level)?;
+    let mut buffer = vec![0; buffer];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        let Some(cells) = NonZeroU32::new(read as u32) else {
+            break;
+        };
+
+        if let Some((bandwidth, handle)) = &bandwidth {
+            handle
+                .block_on(bandwidth.until_n_ready(cells))
+                .map_err(io::Error::other)?;
+        }
+        encoder.write_all(&buffer[..read])?;
+    }
+
+    encoder.finish()?.flush()
+}
+
+/// Whether the target exists and is not older than the source
+async fn is_up_to_date(source: &Path, target: &Path) -> io::Result<bool> {
+    let target = match fs::metadata(target).await {
+        Ok(metadata) => metadata.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let source = fs::metadata(source).await?.modified()?;
+    Ok(target >= source)
+}
+
+static REFLINK_FALLBACK: Once = Once::new();
+
+fn reflink_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let result = reflink::reflink(source, target);
+    #[cfg(not(target_os = "linux"))]
+    let result = Err(io::Error::from(io::ErrorKind::Unsupported));
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            REFLINK_FALLBACK
+                .call_once(|| warn!("Reflink is not supported ({}), copying instead", e));
+            std::fs::copy(source, target).map(|_| ())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod reflink {
+    use std::{fs::File, io, os::fd::AsRawFd, path::Path};
+
+    /// `struct file_clone_range` from `linux/fs.h`
+    #[repr(C)]
+    pub struct FileCloneRange {
+        src_fd: i64,
+        src_offset: u64,
+        src_length: u64,
+        dest_offset: u64,
+    }
+
+    nix::ioctl_write_ptr!(ficlonerange, 0x94, 13, FileCloneRange);
+
+    /// Share the extents of the source with the target, on CoW filesystems
+    pub fn reflink(source: &Path, target: &Path) -> io::Result<()> {
+        let source = File::open(source)?;
+        let target = File::create(target)?;
+
+        // a zero length clones to the end of the source
+        let range = FileCloneRange {
+            src_fd: source.as_raw_fd() as i64,
+            src_offset: 0,
+            src_length: 0,
+            dest_offset: 0,
+        };
+        unsafe { ficlonerange(target.as_raw_fd(), &range) }?;
+        Ok(())
+    }
+}
+
+/// Copy in chunks, waiting for the shared rate limiter before each write
+async fn copy_limited(
+    bandwidth: &DefaultDirectRateLimiter,
+    source: &Path,
+    target: &Path,
+    buffer: usize,
+) -> io::Result<()> {
+    let mut reader = File::open(source).await?;
+    let mut writer = File::create(target).await?;
+    let mut buffer = vec![0; buffer];
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        let Some(cells) = NonZeroU32::new(read as u32) else {
+            break;
+        };
+
+        bandwidth
+            .until_n_ready(cells)
+            .await
+            .map_err(io::Error::other)?;
+        writer.write_all(&buffer[..read]).await?;
+    }
+
+    writer.flush().await
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, error::Error};
+
+use console::style;
+use indicatif::ProgressBar;
+use log::info;
+use post_archiver::{utils::DATABASE_NAME, FileMetaId, Post, PostId};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// Re-extract the extra metadata of archived files missing any key of the current provider.
+///
+/// Returns the number of updated records.
+pub fn refresh_metadata(config: &Config) -> Result<usize, Box<dyn Error>> {
+    let mut conn = Connection::open(config.output().join(DATABASE_NAME))?;
+    // the archived files are read as is
+    let provider = config.without_dim_cache().metadata_provider();
+
+    let records = conn
+        .prepare("SELECT id, post, filename, mime, extra FROM file_metas")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, FileMetaId>(0)?,
+                row.get::<_, PostId>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let progress = config.multi().add(ProgressBar::new(records.len() as u64));
+    progress.set_style(config.progress_style());
+    progress.set_prefix("files");
+
+    let tx = conn.transaction()?;
+    let mut updated = 0;
+    for (id, post, filename, mime, extra) in records {
+        progress.inc(1);
+
+        let mut extra: HashMap<String, Value> = serde_json::from_str(&extra)?;
+        if provider
+            .keys(&mime)
+            .iter()
+            .all(|key| extra.contains_key(*key))
+        {
+            continue;
+        }
+
+        let path = config.output().join(Post::directory(post)).join(&filename);
+        if !path.is_file() {
+            continue;
+        }
+
+        let refreshed = provider.extract(&path, &mime);
+        if refreshed
+            .iter()
+            .all(|(key, value)| extra.get(key) == Some(value))
+        {
+            continue;
+        }
+        extra.extend(refreshed);
+
+        tx.execute(
+            "UPDATE file_metas SET extra = ? WHERE id = ?",
+            params![serde_json::to_string(&extra)?, id],
+        )?;
+        updated += 1;
+    }
+    tx.commit()?;
+
+    let total = progress.length().unwrap();
+    progress.finish_and_clear();
+    info!("{}/{} {}", updated, total, style("records updated").green());
+    Ok(updated)
+}